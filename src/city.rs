@@ -10,6 +10,9 @@ pub struct City {
     pub color: Color,
     pub neighbors: Vec<String>,
     pub has_research_center: bool,
+    // (lat, lon) of the city on the map.
+    #[serde(default)]
+    pub location: Option<(f64, f64)>,
     #[serde(default = "HashMap::new")]
     pub infections: HashMap<Color, u32>,
 }
@@ -22,7 +25,7 @@ impl City {
 
 impl std::fmt::Display for City {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.name.color(self.color.clone()))?;
+        write!(f, "{}", self.name.color(self.color))?;
 
         if self.is_infected() {
             write!(f, " (")?;
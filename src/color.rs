@@ -1,4 +1,5 @@
 use colored::Colorize;
+use image::Rgb;
 use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Default, Copy)]
@@ -10,9 +11,9 @@ pub enum Color {
     Black,
 }
 
-impl Into<colored::Color> for Color {
-    fn into(self) -> colored::Color {
-        match self {
+impl From<Color> for colored::Color {
+    fn from(c: Color) -> colored::Color {
+        match c {
             Color::Blue => colored::Color::Blue,
             Color::Red => colored::Color::Red,
             Color::Yellow => colored::Color::Yellow,
@@ -20,3 +21,14 @@ impl Into<colored::Color> for Color {
         }
     }
 }
+
+impl From<Color> for Rgb<u8> {
+    fn from(c: Color) -> Rgb<u8> {
+        match c {
+            Color::Blue => Rgb([30, 90, 200]),
+            Color::Red => Rgb([200, 30, 30]),
+            Color::Yellow => Rgb([230, 190, 20]),
+            Color::Black => Rgb([40, 40, 40]),
+        }
+    }
+}
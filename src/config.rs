@@ -0,0 +1,88 @@
+use crate::tilesource::TileServerSource;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Settings read from a JSON file; command line flags take precedence.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub tileserver: Option<TileServerConfig>,
+    // URL or path of a TileJSON document, used instead of `tileserver`.
+    pub tilejson: Option<String>,
+    // Local tiles: a z/x/y directory, or an .mbtiles or .pmtiles file. Takes
+    // precedence over both of the above.
+    pub tiles: Option<PathBuf>,
+    pub style: Option<String>,
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TileServerConfig {
+    // A {z}/{x}/{y} URL template or the base URL of a tileserver-gl instance.
+    pub url: String,
+    #[serde(default)]
+    pub subdomains: Vec<String>,
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    // $XDG_CONFIG_HOME/pandemic/config.json, falling back to ~/.config.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match (
+            std::env::var_os("XDG_CONFIG_HOME"),
+            std::env::var_os("HOME"),
+        ) {
+            (Some(config), _) => PathBuf::from(config),
+            (None, Some(home)) => PathBuf::from(home).join(".config"),
+            (None, None) => return None,
+        };
+        Some(base.join("pandemic").join("config.json"))
+    }
+}
+
+impl TileServerConfig {
+    pub fn tile_source(&self) -> TileServerSource {
+        self.query.iter().fold(
+            TileServerSource::new(&self.url).with_subdomains(self.subdomains.clone()),
+            |ts, (k, v)| ts.with_query(k, v),
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("some I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("some serde json error")]
+    SerdeJsonError(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tileserver_config() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "tileserver": {
+                    "url": "https://{s}.example/{z}/{x}/{y}.pbf",
+                    "subdomains": ["a"],
+                    "query": {"key": "abc"}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.style, None);
+
+        let ts = config.tileserver.unwrap().tile_source();
+        assert_eq!(ts.tile_url(1, 0, 1), "https://a.example/1/0/1.pbf?key=abc");
+    }
+}
@@ -0,0 +1,440 @@
+use crate::stats::TileStats;
+use crate::tile;
+use crate::tilesource::{self, Fetched, Freshness, TileSource, TileSourceError};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Keeps temporary file names unique between threads of the same process.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Size in bytes and last use of every cached tile.
+type Index = HashMap<(usize, i32, i32), (u64, SystemTime)>;
+
+// What a cache directory holds, shared by all sources over that directory so
+// that together they stay within one size limit. Read from disk on first use.
+#[derive(Clone, Default)]
+pub struct CacheIndex(Arc<Mutex<Option<IndexState>>>);
+
+struct IndexState {
+    tiles: Index,
+    total_bytes: u64,
+}
+
+// Stores tiles fetched from another source under {root}/{z}/{x}/{y}.pbf, with
+// a {y}.json record next to each holding its ETag and expiry time. Several
+// processes can share one cache directory: files are only ever replaced by
+// atomic renames, so readers see either the old or the new tile.
+pub struct DiskCachedTileSource<TS: TileSource> {
+    ts: TS,
+    root: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: CacheIndex,
+    stats: TileStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CacheRecord {
+    etag: Option<String>,
+    // Seconds since the Unix epoch.
+    expires: u64,
+}
+
+impl<TS: TileSource> DiskCachedTileSource<TS> {
+    pub fn new(root: impl Into<PathBuf>, ts: TS) -> Self {
+        DiskCachedTileSource {
+            ts,
+            root: root.into(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            ttl: DEFAULT_TTL,
+            index: CacheIndex::default(),
+            stats: TileStats::default(),
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    // How long tiles stay fresh when the source doesn't say.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // Shares the index of other sources over the same directory.
+    pub fn with_index(mut self, index: CacheIndex) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn tile_path(&self, z: usize, x: i32, y: i32) -> PathBuf {
+        self.root
+            .join(z.to_string())
+            .join(x.to_string())
+            .join(format!("{y}.pbf"))
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.lock_index(|index| index.total_bytes)
+    }
+
+    fn lock_index<R>(&self, f: impl FnOnce(&mut IndexState) -> R) -> R {
+        let mut index = self.index.0.lock().unwrap();
+        let index = index.get_or_insert_with(|| {
+            let tiles = scan(&self.root);
+            let total_bytes = tiles.values().map(|(size, _)| size).sum();
+            IndexState { tiles, total_bytes }
+        });
+        f(index)
+    }
+
+    fn read(&self, z: usize, x: i32, y: i32) -> Option<(Vec<u8>, CacheRecord)> {
+        let path = self.tile_path(z, x, y);
+        let buf = std::fs::read(&path).ok()?;
+        // A tile without a record is kept, but revalidated before use.
+        let record = std::fs::read(path.with_extension("json"))
+            .ok()
+            .and_then(|r| serde_json::from_slice(&r).ok())
+            .unwrap_or(CacheRecord {
+                etag: None,
+                expires: 0,
+            });
+        Some((buf, record))
+    }
+
+    fn touch(&self, z: usize, x: i32, y: i32) {
+        let now = SystemTime::now();
+        self.lock_index(|index| {
+            if let Some(entry) = index.tiles.get_mut(&(z, x, y)) {
+                entry.1 = now;
+            }
+        });
+        // The modification time doubles as the last use for other processes
+        // and the next session; failing to update it only skews eviction.
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(self.tile_path(z, x, y))
+            .and_then(|f| f.set_modified(now));
+    }
+
+    fn record_for(&self, freshness: &Freshness) -> CacheRecord {
+        let ttl = freshness.max_age.unwrap_or(self.ttl);
+        CacheRecord {
+            etag: freshness.etag.clone(),
+            expires: unix_secs(SystemTime::now() + ttl),
+        }
+    }
+
+    fn store(&self, t: &tile::Tile, freshness: &Freshness) -> Result<(), TileSourceError> {
+        let (z, x, y) = t.zxy;
+        let path = self.tile_path(z, x, y);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let buf = t.vtile.clone().unwrap_or_default().encode_to_vec();
+        write_atomic(&path, &buf)?;
+        let record = serde_json::to_vec(&self.record_for(freshness))?;
+        write_atomic(&path.with_extension("json"), &record)?;
+
+        let size = buf.len() as u64;
+        self.lock_index(|index| {
+            if let Some((old, _)) = index.tiles.insert((z, x, y), (size, SystemTime::now())) {
+                index.total_bytes -= old;
+            }
+            index.total_bytes += size;
+            self.evict(index);
+        });
+        Ok(())
+    }
+
+    // Drops the least recently used tiles until the cache fits its limit.
+    fn evict(&self, index: &mut IndexState) {
+        if index.total_bytes <= self.max_bytes {
+            return;
+        }
+        let mut entries: Vec<_> = index
+            .tiles
+            .iter()
+            .map(|(k, (size, used))| (*used, *k, *size))
+            .collect();
+        entries.sort();
+
+        for (_, (z, x, y), size) in entries {
+            if index.total_bytes <= self.max_bytes {
+                break;
+            }
+            let path = self.tile_path(z, x, y);
+            // Another process sharing the directory may have removed it already.
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(path.with_extension("json"));
+            index.tiles.remove(&(z, x, y));
+            index.total_bytes -= size;
+        }
+    }
+}
+
+impl<TS: TileSource> TileSource for DiskCachedTileSource<TS> {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        self.stats.requests += 1;
+        let cached = self.read(z, x, y);
+        if let Some((buf, record)) = &cached {
+            if record.expires > unix_secs(SystemTime::now()) {
+                match tilesource::decode_tile_timed(&mut self.stats, z, x, y, buf) {
+                    Ok(t) => {
+                        self.stats.hits += 1;
+                        self.touch(z, x, y);
+                        return Ok(t);
+                    }
+                    Err(e) => warn!("Discarding corrupt cached tile {z}/{x}/{y}: {e}"),
+                }
+            }
+        }
+
+        let etag = cached.as_ref().and_then(|(_, r)| r.etag.clone());
+        match self.ts.fetch(z, x, y, etag.as_deref()) {
+            Ok(Fetched::NotModified(freshness)) if cached.is_some() => {
+                let path = self.tile_path(z, x, y).with_extension("json");
+                write_atomic(&path, &serde_json::to_vec(&self.record_for(&freshness))?)?;
+                self.stats.hits += 1;
+                self.touch(z, x, y);
+                tilesource::decode_tile_timed(&mut self.stats, z, x, y, &cached.unwrap().0)
+            }
+            Ok(Fetched::NotModified(_)) => self.ts.get_tile(z, x, y),
+            Ok(Fetched::Tile(t, freshness)) => {
+                if let Err(e) = self.store(&t, &freshness) {
+                    warn!("Could not cache tile {z}/{x}/{y}: {e}");
+                }
+                Ok(t)
+            }
+            // A stale tile beats no tile when the source is unreachable.
+            Err(e) => match cached.and_then(|(buf, _)| tilesource::decode_tile(z, x, y, &buf).ok())
+            {
+                Some(t) => {
+                    warn!("Using stale tile {z}/{x}/{y}: {e}");
+                    Ok(t)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        self.ts.zoom_range()
+    }
+
+    fn stats(&self) -> TileStats {
+        self.stats.with_inner(&self.ts.stats())
+    }
+
+    // Expired tiles are fine as a stand-in.
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        let (buf, _) = self.read(z, x, y)?;
+        tilesource::decode_tile(z, x, y, &buf).ok()
+    }
+}
+
+// $XDG_CACHE_HOME/pandemic/tiles, falling back to ~/.cache and the temp dir.
+pub fn default_cache_dir() -> PathBuf {
+    let base = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => PathBuf::from(home).join(".cache"),
+        (None, None) => std::env::temp_dir(),
+    };
+    base.join("pandemic").join("tiles")
+}
+
+// A directory name for tiles from `url`, so different servers don't mix.
+pub fn cache_subdir(url: &str) -> String {
+    url.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn write_atomic(path: &Path, buf: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&tmp, buf)?;
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+fn scan(root: &Path) -> Index {
+    let mut index = HashMap::new();
+    let parse_dirs = |dir: &Path| -> Vec<(String, PathBuf)> {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| (e.file_name().to_string_lossy().into_owned(), e.path()))
+            .collect()
+    };
+
+    for (z, zdir) in parse_dirs(root) {
+        for (x, xdir) in parse_dirs(&zdir) {
+            for (name, path) in parse_dirs(&xdir) {
+                let y = match name.strip_suffix(".pbf") {
+                    Some(y) => y,
+                    None => continue,
+                };
+                let (z, x, y) = match (z.parse(), x.parse(), y.parse()) {
+                    (Ok(z), Ok(x), Ok(y)) => (z, x, y),
+                    _ => continue,
+                };
+                if let Ok(meta) = std::fs::metadata(&path) {
+                    let used = meta.modified().unwrap_or(UNIX_EPOCH);
+                    index.insert((z, x, y), (meta.len(), used));
+                }
+            }
+        }
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::geometry::Geometry;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Serves one-feature tiles and records the ETags it was asked with.
+    #[derive(Clone, Default)]
+    struct CountingSource {
+        requests: Rc<RefCell<Vec<Option<String>>>>,
+        offline: bool,
+    }
+
+    impl TileSource for CountingSource {
+        fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+            match self.fetch(z, x, y, None)? {
+                Fetched::Tile(t, _) => Ok(t),
+                Fetched::NotModified(_) => unreachable!(),
+            }
+        }
+
+        fn fetch(
+            &mut self,
+            z: usize,
+            x: i32,
+            y: i32,
+            etag: Option<&str>,
+        ) -> Result<Fetched, TileSourceError> {
+            self.requests.borrow_mut().push(etag.map(String::from));
+            if self.offline {
+                return Err(TileSourceError::Timeout);
+            }
+            let freshness = Freshness {
+                etag: Some(format!("\"{z}-{x}-{y}\"")),
+                max_age: None,
+            };
+            if etag.is_some() {
+                return Ok(Fetched::NotModified(freshness));
+            }
+            let mut layer = LayerBuilder::new("place");
+            layer.add_feature(None, &Geometry::MultiPoint(vec![(x, y)]), &HashMap::new());
+            let vtile = TileBuilder::new().add_layer(layer).build();
+            Ok(Fetched::Tile(
+                tile::Tile::from_proto(x, y, z, vtile),
+                freshness,
+            ))
+        }
+    }
+
+    #[test]
+    fn test_disk_cache_reuse_and_revalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = CountingSource::default();
+
+        let mut ts = DiskCachedTileSource::new(dir.path(), upstream.clone());
+        assert_eq!(ts.get_tile(2, 1, 3).unwrap().features("place").count(), 1);
+        ts.get_tile(2, 1, 3).unwrap();
+        assert_eq!(upstream.requests.borrow().len(), 1);
+
+        // A new session over the same directory doesn't hit the source again.
+        let mut ts = DiskCachedTileSource::new(dir.path(), upstream.clone());
+        assert_eq!(ts.get_tile(2, 1, 3).unwrap().zxy, (2, 1, 3));
+        assert_eq!(upstream.requests.borrow().len(), 1);
+
+        // Expired tiles are revalidated with their ETag.
+        let mut ts =
+            DiskCachedTileSource::new(dir.path(), upstream.clone()).with_ttl(Duration::ZERO);
+        ts.get_tile(0, 0, 0).unwrap();
+        ts.get_tile(0, 0, 0).unwrap();
+        assert_eq!(
+            *upstream.requests.borrow(),
+            vec![None, None, Some("\"0-0-0\"".to_string())]
+        );
+
+        // And served stale when the source is gone.
+        let offline = CountingSource {
+            offline: true,
+            ..Default::default()
+        };
+        let mut ts = DiskCachedTileSource::new(dir.path(), offline).with_ttl(Duration::ZERO);
+        assert_eq!(ts.get_tile(0, 0, 0).unwrap().features("place").count(), 1);
+        assert!(ts.get_tile(5, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_disk_cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ts = DiskCachedTileSource::new(dir.path(), CountingSource::default());
+        ts.get_tile(1, 0, 0).unwrap();
+        let tile_size = ts.size_bytes();
+
+        let mut ts = ts.with_max_bytes(2 * tile_size);
+        ts.get_tile(1, 0, 1).unwrap();
+        ts.get_tile(1, 0, 0).unwrap();
+        ts.get_tile(1, 1, 1).unwrap();
+
+        // 1/0/1 was the least recently used.
+        assert!(ts.size_bytes() <= 2 * tile_size);
+        assert!(ts.tile_path(1, 0, 0).exists());
+        assert!(!ts.tile_path(1, 0, 1).exists());
+        assert!(!ts.tile_path(1, 0, 1).with_extension("json").exists());
+        assert!(ts.tile_path(1, 1, 1).exists());
+        assert_eq!(scan(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn test_disk_cache_shared_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = CacheIndex::default();
+        let mut a = DiskCachedTileSource::new(dir.path(), CountingSource::default())
+            .with_index(index.clone());
+        a.get_tile(1, 0, 0).unwrap();
+        let tile_size = a.size_bytes();
+
+        // Sources over one directory stay within its limit together.
+        let mut a = a.with_max_bytes(2 * tile_size);
+        let mut b = DiskCachedTileSource::new(dir.path(), CountingSource::default())
+            .with_max_bytes(2 * tile_size)
+            .with_index(index);
+        b.get_tile(1, 0, 1).unwrap();
+        a.get_tile(1, 1, 0).unwrap();
+        b.get_tile(1, 1, 1).unwrap();
+
+        let on_disk = scan(dir.path());
+        assert_eq!(on_disk.len(), 2);
+        let total: u64 = on_disk.values().map(|(size, _)| size).sum();
+        assert_eq!((a.size_bytes(), b.size_bytes()), (total, total));
+    }
+}
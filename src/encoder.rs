@@ -0,0 +1,331 @@
+use crate::geometry::{self, Geometry};
+use crate::tile::{PropertyValue, Tile};
+use crate::vector_tile::{self, tile::GeomType};
+use std::collections::HashMap;
+
+// https://github.com/mapbox/vector-tile-spec/tree/master/2.1#43-geometry-encoding
+
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+// Collects features for one layer. Keys and values shared between features
+// are only stored once in the layer's tables.
+pub struct LayerBuilder {
+    name: String,
+    extent: u32,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<vector_tile::tile::Value>,
+    value_index: HashMap<ValueKey, u32>,
+    features: Vec<vector_tile::tile::Feature>,
+}
+
+pub struct TileBuilder {
+    layers: Vec<vector_tile::tile::Layer>,
+}
+
+// Floats aren't Eq or Hash, so values are compared by their bit patterns.
+#[derive(Hash, PartialEq, Eq)]
+enum ValueKey {
+    String(String),
+    Float(u32),
+    Double(u64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+impl From<&PropertyValue> for ValueKey {
+    fn from(v: &PropertyValue) -> Self {
+        match v {
+            PropertyValue::String(s) => ValueKey::String(s.clone()),
+            PropertyValue::Float(f) => ValueKey::Float(f.to_bits()),
+            PropertyValue::Double(d) => ValueKey::Double(d.to_bits()),
+            PropertyValue::Int(i) => ValueKey::Int(*i),
+            PropertyValue::UInt(u) => ValueKey::UInt(*u),
+            PropertyValue::SInt(i) => ValueKey::SInt(*i),
+            PropertyValue::Bool(b) => ValueKey::Bool(*b),
+        }
+    }
+}
+
+impl LayerBuilder {
+    pub fn new(name: &str) -> Self {
+        LayerBuilder {
+            name: name.to_string(),
+            extent: 4096,
+            keys: Vec::new(),
+            key_index: HashMap::new(),
+            values: Vec::new(),
+            value_index: HashMap::new(),
+            features: Vec::new(),
+        }
+    }
+
+    pub fn with_extent(mut self, extent: u32) -> Self {
+        self.extent = extent;
+        self
+    }
+
+    pub fn add_feature(
+        &mut self,
+        id: Option<u64>,
+        geometry: &Geometry,
+        properties: &HashMap<String, PropertyValue>,
+    ) {
+        let (geom_type, geometry) = encode_geometry(geometry);
+
+        // Sorted so the same properties always produce the same bytes.
+        let mut properties: Vec<(&String, &PropertyValue)> = properties.iter().collect();
+        properties.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut tags = Vec::with_capacity(2 * properties.len());
+        for (k, v) in properties {
+            tags.push(self.key(k));
+            tags.push(self.value(v));
+        }
+
+        self.features.push(vector_tile::tile::Feature {
+            id,
+            tags,
+            r#type: Some(geom_type as i32),
+            geometry,
+        });
+    }
+
+    pub fn build(self) -> vector_tile::tile::Layer {
+        vector_tile::tile::Layer {
+            version: 2,
+            name: self.name,
+            features: self.features,
+            keys: self.keys,
+            values: self.values,
+            extent: Some(self.extent),
+        }
+    }
+
+    fn key(&mut self, k: &str) -> u32 {
+        if let Some(i) = self.key_index.get(k) {
+            return *i;
+        }
+        let i = self.keys.len() as u32;
+        self.keys.push(k.to_string());
+        self.key_index.insert(k.to_string(), i);
+        i
+    }
+
+    fn value(&mut self, v: &PropertyValue) -> u32 {
+        let i = self.values.len() as u32;
+        let i = *self.value_index.entry(ValueKey::from(v)).or_insert(i);
+        if i as usize == self.values.len() {
+            self.values.push(v.to_proto());
+        }
+        i
+    }
+}
+
+impl TileBuilder {
+    pub fn new() -> Self {
+        TileBuilder { layers: Vec::new() }
+    }
+
+    pub fn add_layer(mut self, layer: LayerBuilder) -> Self {
+        self.layers.push(layer.build());
+        self
+    }
+
+    pub fn build(self) -> vector_tile::Tile {
+        vector_tile::Tile {
+            layers: self.layers,
+        }
+    }
+}
+
+impl Default for TileBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Encodes a geometry in absolute tile coordinates into MVT commands. Polygon
+// rings are rewound if needed so exterior rings come out clockwise and holes
+// counter-clockwise, as the spec requires.
+pub fn encode_geometry(g: &Geometry) -> (GeomType, Vec<u32>) {
+    let mut enc = GeometryEncoder::default();
+    match g {
+        Geometry::MultiPoint(points) => {
+            if !points.is_empty() {
+                enc.command(MOVE_TO, points);
+            }
+            (GeomType::Point, enc.out)
+        }
+        Geometry::MultiLineString(lines) => {
+            for line in lines.iter().filter(|l| l.len() > 1) {
+                enc.command(MOVE_TO, &line[..1]);
+                enc.command(LINE_TO, &line[1..]);
+            }
+            (GeomType::Linestring, enc.out)
+        }
+        Geometry::MultiPolygon(polygons) => {
+            for rings in polygons {
+                let rings: Vec<Vec<(i32, i32)>> = rings
+                    .iter()
+                    .map(|ring| {
+                        let mut ring = ring.clone();
+                        ring.dedup();
+                        if ring.len() > 1 && ring.first() == ring.last() {
+                            ring.pop();
+                        }
+                        ring
+                    })
+                    .collect();
+                // Holes without their exterior ring would be read as holes of
+                // the polygon before, so the whole polygon is left out.
+                match rings.first() {
+                    Some(exterior) if exterior.len() >= 3 => {}
+                    _ => continue,
+                }
+                for (i, mut ring) in rings.into_iter().enumerate() {
+                    if ring.len() < 3 {
+                        continue;
+                    }
+                    let exterior = i == 0;
+                    if (geometry::signed_area(&ring) > 0) != exterior {
+                        ring.reverse();
+                    }
+                    enc.command(MOVE_TO, &ring[..1]);
+                    enc.command(LINE_TO, &ring[1..]);
+                    enc.out.push(Tile::command_integer(CLOSE_PATH, 1));
+                }
+            }
+            (GeomType::Polygon, enc.out)
+        }
+    }
+}
+
+#[derive(Default)]
+struct GeometryEncoder {
+    cursor: (i32, i32),
+    out: Vec<u32>,
+}
+
+impl GeometryEncoder {
+    fn command(&mut self, c_id: u32, points: &[(i32, i32)]) {
+        self.out
+            .push(Tile::command_integer(c_id, points.len() as u32));
+        for p in points {
+            self.out
+                .push(Tile::encode_parameter_integer(p.0 - self.cursor.0));
+            self.out
+                .push(Tile::encode_parameter_integer(p.1 - self.cursor.1));
+            self.cursor = *p;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::Feature;
+    use prost::Message;
+
+    #[test]
+    fn test_encode_geometry() {
+        // The example from the spec, section 4.3.5.3
+        let g = Geometry::MultiPolygon(vec![vec![vec![(3, 6), (8, 12), (20, 34), (3, 6)]]]);
+        assert_eq!(
+            encode_geometry(&g),
+            (GeomType::Polygon, vec![9, 6, 12, 18, 10, 12, 24, 44, 15])
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let geometries = [
+            Geometry::MultiPoint(vec![(5, 7), (3, 2)]),
+            Geometry::MultiLineString(vec![vec![(2, 2), (2, 10), (10, 10)], vec![(1, 1), (3, 5)]]),
+            Geometry::MultiPolygon(vec![
+                vec![
+                    vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)],
+                    vec![(2, 3), (2, 5), (4, 5), (4, 3), (2, 3)],
+                ],
+                vec![vec![(17, 3), (19, 3), (19, 5), (17, 5), (17, 3)]],
+            ]),
+        ];
+        let properties: Vec<HashMap<String, PropertyValue>> = vec![
+            [
+                (
+                    String::from("name"),
+                    PropertyValue::String(String::from("Atlanta")),
+                ),
+                (String::from("rank"), PropertyValue::UInt(3)),
+            ]
+            .into(),
+            [(String::from("rank"), PropertyValue::UInt(3))].into(),
+            [
+                (
+                    String::from("name"),
+                    PropertyValue::String(String::from("Lake")),
+                ),
+                (String::from("area"), PropertyValue::Double(12.5)),
+            ]
+            .into(),
+        ];
+
+        let mut layer = LayerBuilder::new("overlay").with_extent(512);
+        for (i, (g, props)) in geometries.iter().zip(&properties).enumerate() {
+            layer.add_feature(Some(i as u64), g, props);
+        }
+        let buf = TileBuilder::new().add_layer(layer).build().encode_to_vec();
+
+        let vtile = vector_tile::Tile::decode(buf.as_slice()).unwrap();
+        let layer = &vtile.layers[0];
+        assert_eq!(layer.name, "overlay");
+        assert_eq!(layer.extent(), 512);
+        assert_eq!(layer.keys.len(), 3);
+        assert_eq!(layer.values.len(), 4);
+
+        for (i, f) in layer.features.iter().enumerate() {
+            let f = Feature::new(layer, f);
+            assert_eq!(f.id(), Some(i as u64));
+            assert_eq!(f.geometry().as_ref(), Ok(&geometries[i]));
+            assert_eq!(f.properties, properties[i]);
+        }
+    }
+
+    #[test]
+    fn test_rewinds_rings() {
+        let cw = vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)];
+        let ccw: Vec<(i32, i32)> = cw.iter().rev().cloned().collect();
+        let (_, a) = encode_geometry(&Geometry::MultiPolygon(vec![vec![cw.clone()]]));
+        let (_, b) = encode_geometry(&Geometry::MultiPolygon(vec![vec![ccw]]));
+
+        let decode = |g: &[u32]| {
+            Geometry::from_commands(
+                GeomType::Polygon,
+                &Tile::decode_geometry(GeomType::Polygon, g).unwrap(),
+            )
+        };
+        assert_eq!(decode(&a), Ok(Geometry::MultiPolygon(vec![vec![cw]])));
+        assert!(matches!(decode(&b), Ok(Geometry::MultiPolygon(p)) if p.len() == 1));
+    }
+
+    #[test]
+    fn test_skips_collapsed_polygons() {
+        let cw = vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)];
+        let collapsed = vec![(20, 20), (20, 20), (30, 30), (20, 20)];
+        let hole = vec![(22, 22), (22, 28), (28, 28), (28, 22), (22, 22)];
+        let (_, g) = encode_geometry(&Geometry::MultiPolygon(vec![
+            vec![cw.clone()],
+            vec![collapsed, hole],
+        ]));
+
+        let commands = Tile::decode_geometry(GeomType::Polygon, &g).unwrap();
+        assert_eq!(
+            Geometry::from_commands(GeomType::Polygon, &commands),
+            Ok(Geometry::MultiPolygon(vec![vec![cw]]))
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use crate::tile::{GeometryCommand, GeometryError};
+use crate::vector_tile::tile::GeomType;
+use simplify_polyline as sp;
+
+// https://github.com/mapbox/vector-tile-spec/tree/master/2.1#4344-polygon-geometry-type
+
+// A decoded feature geometry. Points are absolute tile coordinates by default.
+// Polygons are lists of rings, exterior ring first, and every ring is closed
+// (its last point repeats the first one).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry<P = (i32, i32)> {
+    MultiPoint(Vec<P>),
+    MultiLineString(Vec<Vec<P>>),
+    MultiPolygon(Vec<Vec<Vec<P>>>),
+}
+
+impl Geometry {
+    // Replays validated commands (see Tile::decode_geometry) with a cursor.
+    pub fn from_commands(
+        geom_type: GeomType,
+        commands: &[GeometryCommand],
+    ) -> Result<Self, GeometryError> {
+        let mut lines: Vec<Vec<(i32, i32)>> = Vec::new();
+        let mut line: Vec<(i32, i32)> = Vec::new();
+        let mut points = Vec::new();
+
+        let mut cursor = (0, 0);
+        for c in commands {
+            match c {
+                GeometryCommand::MoveTo(dx, dy) => {
+                    cursor = (cursor.0 + dx, cursor.1 + dy);
+                    points.push(cursor);
+                    if line.len() > 1 {
+                        lines.push(line);
+                    }
+                    line = vec![cursor];
+                }
+                GeometryCommand::LineTo(dx, dy) => {
+                    if line.is_empty() {
+                        line.push(cursor);
+                    }
+                    cursor = (cursor.0 + dx, cursor.1 + dy);
+                    line.push(cursor);
+                }
+                GeometryCommand::ClosePath => {
+                    if let Some(first) = line.first() {
+                        line.push(*first);
+                    }
+                }
+            }
+        }
+        if line.len() > 1 {
+            lines.push(line);
+        }
+
+        match geom_type {
+            GeomType::Point => Ok(Geometry::MultiPoint(points)),
+            GeomType::Linestring => Ok(Geometry::MultiLineString(lines)),
+            GeomType::Polygon => Ok(Geometry::MultiPolygon(classify_rings(lines))),
+            GeomType::Unknown => Err(GeometryError::UnknownGeometryType),
+        }
+    }
+}
+
+impl<P: Copy> Geometry<P> {
+    pub fn map<Q>(&self, mut f: impl FnMut(P) -> Q) -> Geometry<Q> {
+        match self {
+            Geometry::MultiPoint(points) => {
+                Geometry::MultiPoint(points.iter().map(|p| f(*p)).collect())
+            }
+            Geometry::MultiLineString(lines) => Geometry::MultiLineString(
+                lines
+                    .iter()
+                    .map(|line| line.iter().map(|p| f(*p)).collect())
+                    .collect(),
+            ),
+            Geometry::MultiPolygon(polygons) => Geometry::MultiPolygon(
+                polygons
+                    .iter()
+                    .map(|rings| {
+                        rings
+                            .iter()
+                            .map(|ring| ring.iter().map(|p| f(*p)).collect())
+                            .collect()
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // Everything that can be drawn as a polyline: linestrings, or all rings
+    // of all polygons. Points have no lines.
+    pub fn into_lines(self) -> Vec<Vec<P>> {
+        match self {
+            Geometry::MultiPoint(_) => Vec::new(),
+            Geometry::MultiLineString(lines) => lines,
+            Geometry::MultiPolygon(polygons) => polygons.into_iter().flatten().collect(),
+        }
+    }
+}
+
+// Twice the signed area of a ring (shoelace formula). In tile coordinates,
+// where y points down, exterior rings are positive and interior rings negative.
+pub fn signed_area(ring: &[(i32, i32)]) -> i64 {
+    if ring.is_empty() {
+        return 0;
+    }
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(p, q)| p.0 as i64 * q.1 as i64 - q.0 as i64 * p.1 as i64)
+        .sum()
+}
+
+// Groups the rings of a polygon feature into polygons. Each polygon is an
+// exterior ring followed by its holes. Degenerate rings and holes that appear
+// before any exterior ring are dropped.
+pub fn classify_rings(rings: Vec<Vec<(i32, i32)>>) -> Vec<Vec<Vec<(i32, i32)>>> {
+    let mut polygons: Vec<Vec<Vec<(i32, i32)>>> = Vec::new();
+    for ring in rings {
+        let area = signed_area(&ring);
+        if area > 0 {
+            polygons.push(vec![ring]);
+        } else if area < 0 {
+            if let Some(polygon) = polygons.last_mut() {
+                polygon.push(ring);
+            }
+        }
+    }
+    polygons
+}
+
+// Horizontal pixel spans (y, x_start, x_end) covering an exterior ring minus
+// its holes, with both ends inclusive. Pixels are sampled at their centers
+// using the even-odd rule, and only pixels inside the clip rectangle
+// ((x0, y0), (x1, y1)), end exclusive, are returned.
+pub fn scanline_fill(
+    rings: &[Vec<sp::Point<f32>>],
+    clip: ((i32, i32), (i32, i32)),
+) -> Vec<(i32, i32, i32)> {
+    let ((cx0, cy0), (cx1, cy1)) = clip;
+
+    let (mut ymin, mut ymax) = (f32::MAX, f32::MIN);
+    for p in rings.iter().flatten() {
+        ymin = ymin.min(p.y);
+        ymax = ymax.max(p.y);
+    }
+    if ymin > ymax {
+        return Vec::new();
+    }
+
+    let mut spans = Vec::new();
+    let mut xs: Vec<f32> = Vec::new();
+    for y in (ymin.floor() as i32).max(cy0)..(ymax.ceil() as i32 + 1).min(cy1) {
+        let sy = y as f32 + 0.5;
+
+        xs.clear();
+        for ring in rings {
+            for (p, q) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                if (p.y <= sy && sy < q.y) || (q.y <= sy && sy < p.y) {
+                    xs.push(p.x + (sy - p.y) / (q.y - p.y) * (q.x - p.x));
+                }
+            }
+        }
+        xs.sort_by(|a, b| a.total_cmp(b));
+
+        for pair in xs.chunks_exact(2) {
+            let x0 = ((pair[0] - 0.5).ceil() as i32).max(cx0);
+            let x1 = ((pair[1] - 0.5).floor() as i32).min(cx1 - 1);
+            if x0 <= x1 {
+                spans.push((y, x0, x1));
+            }
+        }
+    }
+    spans
+}
+
+// Cuts the segment p-q down to the part inside the clip rectangle
+// ((x0, y0), (x1, y1)), end exclusive (Liang-Barsky).
+pub fn clip_segment(
+    p: (i32, i32),
+    q: (i32, i32),
+    clip: ((i32, i32), (i32, i32)),
+) -> Option<((i32, i32), (i32, i32))> {
+    let ((cx0, cy0), (cx1, cy1)) = clip;
+    let (x0, y0) = (p.0 as f64, p.1 as f64);
+    let (dx, dy) = ((q.0 - p.0) as f64, (q.1 - p.1) as f64);
+
+    let (mut t0, mut t1) = (0f64, 1f64);
+    for (d, dist) in [
+        (-dx, x0 - cx0 as f64),
+        (dx, (cx1 - 1) as f64 - x0),
+        (-dy, y0 - cy0 as f64),
+        (dy, (cy1 - 1) as f64 - y0),
+    ] {
+        if d == 0. {
+            if dist < 0. {
+                return None;
+            }
+        } else if d < 0. {
+            t0 = t0.max(dist / d);
+        } else {
+            t1 = t1.min(dist / d);
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    let at = |t: f64| ((x0 + t * dx).round() as i32, (y0 + t * dy).round() as i32);
+    Some((at(t0), at(t1)))
+}
+
+// Cuts a polyline down to the parts inside the clip rectangle
+// ((x0, y0), (x1, y1)), edges included. Each part comes out as its own line.
+pub fn clip_line(line: &[(i32, i32)], clip: ((i32, i32), (i32, i32))) -> Vec<Vec<(i32, i32)>> {
+    let ((x0, y0), (x1, y1)) = clip;
+    let mut parts: Vec<Vec<(i32, i32)>> = Vec::new();
+    for (p, q) in line.iter().zip(line.iter().skip(1)) {
+        if let Some((p, q)) = clip_segment(*p, *q, ((x0, y0), (x1 + 1, y1 + 1))) {
+            match parts.last_mut() {
+                Some(part) if part.last() == Some(&p) => part.push(q),
+                _ => parts.push(vec![p, q]),
+            }
+        }
+    }
+    parts
+}
+
+// Cuts a ring down to the part inside the clip rectangle ((x0, y0), (x1, y1)),
+// edges included (Sutherland-Hodgman). Parts of the ring outside are replaced
+// by the rectangle's edges. The result is closed, or empty if nothing is left.
+pub fn clip_ring(ring: &[(i32, i32)], clip: ((i32, i32), (i32, i32))) -> Vec<(i32, i32)> {
+    let ((x0, y0), (x1, y1)) = clip;
+    let mut points = ring.to_vec();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+
+    for (axis, bound, keep_above) in [(0, x0, true), (0, x1, false), (1, y0, true), (1, y1, false)]
+    {
+        let coord = |p: (i32, i32)| if axis == 0 { p.0 } else { p.1 };
+        let inside = |p: (i32, i32)| {
+            if keep_above {
+                coord(p) >= bound
+            } else {
+                coord(p) <= bound
+            }
+        };
+        let crossing = |p: (i32, i32), q: (i32, i32)| {
+            let t = (bound - coord(p)) as f64 / (coord(q) - coord(p)) as f64;
+            let at = |a: i32, b: i32| (a as f64 + t * (b - a) as f64).round() as i32;
+            if axis == 0 {
+                (bound, at(p.1, q.1))
+            } else {
+                (at(p.0, q.0), bound)
+            }
+        };
+
+        let input = std::mem::take(&mut points);
+        for (i, &q) in input.iter().enumerate() {
+            let p = input[(i + input.len() - 1) % input.len()];
+            if inside(q) {
+                if !inside(p) {
+                    points.push(crossing(p, q));
+                }
+                points.push(q);
+            } else if inside(p) {
+                points.push(crossing(p, q));
+            }
+        }
+    }
+
+    if points.len() < 3 {
+        return Vec::new();
+    }
+    points.push(points[0]);
+    points
+}
+
+// Splits segments where they cross a grid of `step` units, so that they can
+// follow the curve a projection bends them into. Segments along the same line
+// are split at the same points, so edges that rings share stay shared.
+pub fn densify(line: &[(i32, i32)], step: i32) -> Vec<(i32, i32)> {
+    let mut points = Vec::with_capacity(line.len());
+    for (p, q) in line.iter().zip(line.iter().skip(1)) {
+        let mut ts: Vec<f64> = Vec::new();
+        for (a, b) in [(p.0, q.0), (p.1, q.1)] {
+            let mut k = a.min(b).div_euclid(step) + 1;
+            while k * step < a.max(b) {
+                ts.push((k * step - a) as f64 / (b - a) as f64);
+                k += 1;
+            }
+        }
+        ts.sort_by(|a, b| a.total_cmp(b));
+
+        points.push(*p);
+        for t in ts {
+            let r = (
+                (p.0 as f64 + t * (q.0 - p.0) as f64).round() as i32,
+                (p.1 as f64 + t * (q.1 - p.1) as f64).round() as i32,
+            );
+            if points.last() != Some(&r) {
+                points.push(r);
+            }
+        }
+    }
+    points.extend(line.last());
+    points
+}
+
+// Whether `p` is inside a polygon, given as its exterior ring and holes, by
+// the even-odd rule.
+pub fn point_in_polygon(p: (f64, f64), rings: &[Vec<(f64, f64)>]) -> bool {
+    let mut inside = false;
+    for ring in rings {
+        for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+            if (a.1 <= p.1) != (b.1 <= p.1) && p.0 < a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+// Distance from `p` to the nearest point of a line, or of a single point.
+pub fn distance_to_line(p: (f64, f64), line: &[(f64, f64)]) -> f64 {
+    let distance = |a: &(f64, f64), b: &(f64, f64)| {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let len2 = dx * dx + dy * dy;
+        let t = if len2 == 0. {
+            0.
+        } else {
+            (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0., 1.)
+        };
+        (a.0 + t * dx - p.0).hypot(a.1 + t * dy - p.1)
+    };
+    match line {
+        [] => f64::INFINITY,
+        [a] => distance(a, a),
+        _ => line
+            .iter()
+            .zip(line.iter().skip(1))
+            .map(|(a, b)| distance(a, b))
+            .fold(f64::INFINITY, f64::min),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_segment() {
+        let clip = ((0, 0), (10, 10));
+        assert_eq!(clip_segment((1, 1), (5, 5), clip), Some(((1, 1), (5, 5))));
+        assert_eq!(
+            clip_segment((-10, 5), (20, 5), clip),
+            Some(((0, 5), (9, 5)))
+        );
+        assert_eq!(
+            clip_segment((-5, -5), (1000, 1000), clip),
+            Some(((0, 0), (9, 9)))
+        );
+        assert_eq!(clip_segment((-5, 20), (20, 20), clip), None);
+        assert_eq!(clip_segment((-5, 3), (-1, 8), clip), None);
+    }
+
+    #[test]
+    fn test_clip_line_and_ring() {
+        let clip = ((0, 0), (10, 10));
+        assert_eq!(
+            clip_line(&[(-5, 5), (5, 5), (5, 20), (8, 20), (8, 2)], clip),
+            vec![vec![(0, 5), (5, 5), (5, 10)], vec![(8, 10), (8, 2)]]
+        );
+
+        // A square sticking out of the top right corner, and one outside.
+        assert_eq!(
+            clip_ring(&square(5, -5, 10), clip),
+            vec![(5, 0), (10, 0), (10, 5), (5, 5), (5, 0)]
+        );
+        assert_eq!(clip_ring(&square(20, 20, 5), clip), vec![]);
+
+        assert_eq!(
+            densify(&[(0, 0), (10, 0), (10, 3)], 4),
+            vec![(0, 0), (4, 0), (8, 0), (10, 0), (10, 3)]
+        );
+    }
+
+    #[test]
+    fn test_hit_tests() {
+        let outer = vec![(0., 0.), (10., 0.), (10., 10.), (0., 10.), (0., 0.)];
+        let hole = vec![(4., 4.), (6., 4.), (6., 6.), (4., 6.), (4., 4.)];
+        let polygon = [outer.clone(), hole];
+        assert!(point_in_polygon((2., 3.), &polygon));
+        assert!(!point_in_polygon((5., 5.), &polygon));
+        assert!(!point_in_polygon((12., 5.), &polygon));
+
+        assert_eq!(distance_to_line((5., 3.), &outer), 3.);
+        assert_eq!(distance_to_line((13., 14.), &outer), 5.);
+        assert_eq!(distance_to_line((3., 4.), &[(0., 0.)]), 5.);
+        assert_eq!(distance_to_line((3., 4.), &[]), f64::INFINITY);
+    }
+
+    fn square(x: i32, y: i32, size: i32) -> Vec<(i32, i32)> {
+        vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)]
+    }
+
+    fn to_points(ring: &[(i32, i32)]) -> Vec<sp::Point<f32>> {
+        ring.iter()
+            .map(|p| sp::Point {
+                x: p.0 as f32,
+                y: p.1 as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_from_commands() {
+        use crate::tile::Tile;
+
+        let points = Tile::decode_geometry(GeomType::Point, &[17, 10, 14, 3, 9]).unwrap();
+        assert_eq!(
+            Geometry::from_commands(GeomType::Point, &points),
+            Ok(Geometry::MultiPoint(vec![(5, 7), (3, 2)]))
+        );
+
+        let lines =
+            Tile::decode_geometry(GeomType::Linestring, &[9, 4, 4, 18, 0, 16, 16, 0, 9, 2, 2])
+                .unwrap();
+        assert_eq!(
+            Geometry::from_commands(GeomType::Linestring, &lines),
+            Ok(Geometry::MultiLineString(vec![vec![
+                (2, 2),
+                (2, 10),
+                (10, 10)
+            ]]))
+        );
+
+        // An exterior ring with one hole, followed by a second polygon.
+        let polygons = Tile::decode_geometry(
+            GeomType::Polygon,
+            &[
+                9, 0, 0, 26, 20, 0, 0, 20, 19, 0, 15, 9, 4, 13, 26, 0, 4, 4, 0, 0, 3, 15, 9, 26, 0,
+                26, 4, 0, 0, 4, 3, 0, 15,
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            Geometry::from_commands(GeomType::Polygon, &polygons),
+            Ok(Geometry::MultiPolygon(vec![
+                vec![
+                    vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)],
+                    vec![(2, 3), (2, 5), (4, 5), (4, 3), (2, 3)],
+                ],
+                vec![vec![(17, 3), (19, 3), (19, 5), (17, 5), (17, 3)]],
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_classify_rings() {
+        let outer = square(0, 0, 10);
+        let mut hole = square(2, 2, 2);
+        hole.reverse();
+        let other = square(20, 20, 5);
+
+        assert!(signed_area(&outer) > 0);
+        assert!(signed_area(&hole) < 0);
+
+        let polygons = classify_rings(vec![
+            hole.clone(),
+            outer.clone(),
+            hole.clone(),
+            other.clone(),
+            vec![(1, 1), (2, 2)],
+        ]);
+        assert_eq!(polygons, vec![vec![outer, hole], vec![other]]);
+    }
+
+    #[test]
+    fn test_scanline_fill() {
+        let outer = to_points(&square(0, 0, 4));
+        let mut hole = to_points(&square(1, 1, 2));
+        hole.reverse();
+
+        let spans = scanline_fill(std::slice::from_ref(&outer), ((0, 0), (100, 100)));
+        assert_eq!(spans, vec![(0, 0, 3), (1, 0, 3), (2, 0, 3), (3, 0, 3)]);
+
+        let spans = scanline_fill(&[outer.clone(), hole], ((0, 0), (100, 100)));
+        assert_eq!(
+            spans,
+            vec![
+                (0, 0, 3),
+                (1, 0, 0),
+                (1, 3, 3),
+                (2, 0, 0),
+                (2, 3, 3),
+                (3, 0, 3)
+            ]
+        );
+
+        let spans = scanline_fill(&[outer], ((1, 2), (3, 3)));
+        assert_eq!(spans, vec![(2, 1, 2)]);
+    }
+}
@@ -16,10 +16,22 @@ use thiserror::Error;
 
 pub mod city;
 pub mod color;
+pub mod config;
 pub mod disease;
+pub mod diskcache;
+pub mod encoder;
+pub mod geometry;
+pub mod loader;
+pub mod mbtiles;
 pub mod player;
+pub mod pmtiles;
+pub mod projection;
 pub mod renderer;
+pub mod stats;
+pub mod style;
+pub mod svg;
 pub mod tile;
+pub mod tilejson;
 pub mod tilesource;
 pub mod util;
 pub use crate::city::City;
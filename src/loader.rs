@@ -0,0 +1,241 @@
+use crate::stats::TileStats;
+use crate::tile;
+use crate::tilesource::{self, CacheEntry, TileSource, TileSourceError};
+use std::collections::{HashSet, VecDeque};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+type Key = (usize, i32, i32);
+// A finished tile, sent along with the worker's stats so far.
+type Message = (usize, Key, Result<tile::Tile, TileSourceError>, TileStats);
+
+// Loads tiles on a pool of background threads. get_tile never blocks: tiles
+// that aren't loaded yet come back as `Loading` and are queued, and poll()
+// picks up whatever the workers finished since the last call.
+pub struct TileLoader {
+    shared: Arc<Shared>,
+    results: Receiver<Message>,
+    // Tiles that are queued or being fetched right now.
+    in_flight: HashSet<Key>,
+    // Failures are kept until they're DEFAULT_ERROR_TTL old.
+    ready: lru::LruCache<Key, CacheEntry>,
+    zoom_range: (u32, u32),
+    stats: TileStats,
+    worker_stats: Vec<TileStats>,
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<Key>>,
+    wakeup: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl TileLoader {
+    // Starts one worker per source, so that they can fetch in parallel. The
+    // sources should share any persistent cache, and `cap` tiles are kept
+    // in memory.
+    pub fn new(sources: Vec<Box<dyn TileSource + Send>>, cap: NonZeroUsize) -> Self {
+        let zoom_range = sources.first().map_or((0, 0), |ts| ts.zoom_range());
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::new()),
+            wakeup: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        let (tx, results) = mpsc::channel();
+
+        let workers = sources.len();
+        for (i, ts) in sources.into_iter().enumerate() {
+            let shared = shared.clone();
+            let tx = tx.clone();
+            thread::spawn(move || work(i, ts, &shared, tx));
+        }
+
+        TileLoader {
+            shared,
+            results,
+            in_flight: HashSet::new(),
+            ready: lru::LruCache::new(cap),
+            zoom_range,
+            stats: TileStats::default(),
+            worker_stats: vec![TileStats::default(); workers],
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        !self.in_flight.is_empty()
+    }
+
+    fn is_ready(&self, k: &Key) -> bool {
+        match self.ready.peek(k) {
+            Some((Ok(_), _)) => true,
+            Some((Err(_), at)) => at.elapsed() < tilesource::DEFAULT_ERROR_TTL,
+            None => false,
+        }
+    }
+}
+
+fn work(i: usize, mut ts: Box<dyn TileSource + Send>, shared: &Shared, tx: Sender<Message>) {
+    loop {
+        let (z, x, y) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if shared.shutdown.load(Ordering::Relaxed) {
+                    return;
+                }
+                match queue.pop_front() {
+                    Some(k) => break k,
+                    None => queue = shared.wakeup.wait(queue).unwrap(),
+                }
+            }
+        };
+        let res = ts.get_tile(z, x, y);
+        if tx.send((i, (z, x, y), res, ts.stats())).is_err() {
+            return;
+        }
+    }
+}
+
+impl TileSource for TileLoader {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let k = (z, x, y);
+        self.stats.requests += 1;
+        if self.is_ready(&k) {
+            self.stats.hits += 1;
+            return self.ready.get(&k).unwrap().0.clone();
+        }
+        if self.in_flight.insert(k) {
+            self.shared.queue.lock().unwrap().push_back(k);
+            self.shared.wakeup.notify_one();
+        }
+        Err(TileSourceError::Loading(z, x, y))
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        self.zoom_range
+    }
+
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        match self.ready.peek(&(z, x, y)) {
+            Some((Ok(tile), _)) => Some(tile.to_owned()),
+            _ => None,
+        }
+    }
+
+    // Replaces the queue with the wanted tiles that aren't loaded or being
+    // fetched, which drops queued tiles that went off screen.
+    fn request(&mut self, tiles: &[(usize, i32, i32)]) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        for k in queue.drain(..) {
+            self.in_flight.remove(&k);
+        }
+        for &k in tiles {
+            if !self.is_ready(&k) && self.in_flight.insert(k) {
+                queue.push_back(k);
+            }
+        }
+        self.shared.wakeup.notify_all();
+    }
+
+    fn poll(&mut self) -> bool {
+        let mut arrived = false;
+        for (i, k, res, stats) in self.results.try_iter() {
+            self.worker_stats[i] = stats;
+            if let Err(e) = &res {
+                error!("Could not get vector tile {}/{}/{}: {}", k.0, k.1, k.2, e);
+            }
+            self.in_flight.remove(&k);
+            self.ready.put(k, (res, Instant::now()));
+            arrived = true;
+        }
+        arrived
+    }
+
+    fn stats(&self) -> TileStats {
+        let mut inner = TileStats::default();
+        for stats in &self.worker_stats {
+            inner.add(stats);
+        }
+        self.stats.with_inner(&inner)
+    }
+}
+
+impl Drop for TileLoader {
+    // Workers finish the fetch they are in and exit.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        let _queue = self.shared.queue.lock().unwrap();
+        self.shared.wakeup.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_tile;
+    use std::time::{Duration, Instant};
+
+    // Records every tile it's asked for, then waits for the test to let it go.
+    struct GatedSource {
+        fetched: Arc<Mutex<Vec<Key>>>,
+        gate: Arc<Mutex<Receiver<()>>>,
+    }
+
+    impl TileSource for GatedSource {
+        fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+            self.fetched.lock().unwrap().push((z, x, y));
+            self.gate.lock().unwrap().recv().unwrap();
+            Ok(tile::Tile::from_proto(
+                x,
+                y,
+                z,
+                vector_tile::Tile::default(),
+            ))
+        }
+    }
+
+    fn wait_until(mut f: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_tile_loader() {
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let (open, gate) = mpsc::channel();
+        let ts = GatedSource {
+            fetched: fetched.clone(),
+            gate: Arc::new(Mutex::new(gate)),
+        };
+        let mut loader = TileLoader::new(vec![Box::new(ts)], NonZeroUsize::new(16).unwrap());
+
+        let (a, b, c, d) = ((1, 0, 0), (1, 1, 0), (1, 0, 1), (1, 1, 1));
+        loader.request(&[a, b, c]);
+        wait_until(|| fetched.lock().unwrap().len() == 1);
+        assert!(matches!(
+            loader.get_tile(1, 0, 0),
+            Err(TileSourceError::Loading(1, 0, 0))
+        ));
+
+        // b went off screen before its turn came.
+        loader.request(&[d, c, a]);
+        for _ in 0..3 {
+            open.send(()).unwrap();
+        }
+        wait_until(|| {
+            loader.poll();
+            !loader.is_loading()
+        });
+
+        assert_eq!(*fetched.lock().unwrap(), vec![a, d, c]);
+        assert!(loader.get_tile(1, 1, 1).is_ok());
+        assert!(loader.cached_tile(1, 0, 1).is_some());
+        assert!(loader.cached_tile(1, 1, 0).is_none());
+    }
+}
@@ -5,33 +5,390 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-use clap::Parser;
-use pancurses;
-use pancurses::{endwin, initscr, Input};
-use pandemic;
-use pandemic::renderer::Renderer;
+use clap::builder::PossibleValuesParser;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use image::Rgb;
+use pancurses::{endwin, initscr, Input, Window};
+use pandemic::config::{Config, TileServerConfig};
+use pandemic::diskcache::{self, CacheIndex, DiskCachedTileSource};
+use pandemic::loader::TileLoader;
+use pandemic::mbtiles::MbtilesTileSource;
+use pandemic::pmtiles::PmtilesTileSource;
+use pandemic::projection;
+use pandemic::renderer::{
+    BrailleRenderer, ColorMode, Hit, ImageRenderer, Renderer, ScreenSpace, MAX_OVERZOOM,
+};
+use pandemic::style::Style;
+use pandemic::svg::SvgRenderer;
+use pandemic::tilejson::TileJson;
+use pandemic::tilesource::{self, CachedTileSource, DirectoryTileSource, TileSource};
+use pandemic::City;
 use prost::Message;
-use reqwest;
 use std::collections::HashMap;
+use std::fs;
 use std::io::prelude::*;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+// Decoded tiles kept in memory; everything else is read back from the disk cache.
+const MEMORY_CACHE_TILES: usize = 256;
+const LOADER_THREADS: usize = 4;
+// How often the UI checks for newly loaded tiles while waiting for a key.
+const POLL_INTERVAL_MS: i32 = 100;
 
 #[derive(Parser)]
 struct Cli {
-    #[clap(long, default_value_t = String::from("http://harababurel.com:8080"))]
-    tileserver: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// JSON config file; defaults to ~/.config/pandemic/config.json when present.
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    /// Tile URL template with {z}/{x}/{y} (and optionally {s}, {-y}), or a tileserver-gl base URL.
+    #[clap(long, global = true)]
+    tileserver: Option<String>,
+    /// URL or file of a TileJSON document describing the tile server.
+    #[clap(long, global = true, conflicts_with = "tileserver")]
+    tilejson: Option<String>,
+    /// Local tiles: a {z}/{x}/{y}.pbf directory, or an .mbtiles or .pmtiles file.
+    #[clap(long, global = true, conflicts_with_all = ["tileserver", "tilejson"])]
+    tiles: Option<PathBuf>,
+    /// Comma-separated subdomains substituted for {s} in the tile URL.
+    #[clap(long, global = true, value_delimiter = ',')]
+    subdomains: Vec<String>,
+    /// Extra query parameter for tile requests, e.g. --tile-query key=abc.
+    #[clap(long, global = true, value_parser = parse_key_val)]
+    tile_query: Vec<(String, String)>,
     // #[clap(long, default_value_t = 8.55)]
     // lon: f64,
     // #[clap(long, default_value_t = 47.3667)]
     // lat: f64,
-    #[clap(long, default_value_t = -74.)]
+    #[clap(long, global = true, default_value_t = -74., allow_negative_numbers = true)]
     lon: f64,
-    #[clap(long, default_value_t = 40.71)]
+    #[clap(
+        long,
+        global = true,
+        default_value_t = 40.71,
+        allow_negative_numbers = true
+    )]
     lat: f64,
+    /// JSON map style; the built-in style is used when omitted.
+    #[clap(long, global = true)]
+    style: Option<String>,
+    #[clap(long, global = true, default_value = "mercator", value_parser = PossibleValuesParser::new(projection::NAMES))]
+    projection: String,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a single frame without the interactive UI and exit.
+    Render(RenderArgs),
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    #[clap(long, default_value_t = 2)]
+    zoom: u32,
+    /// WIDTHxHEIGHT in pixels, or in braille dots (two by four per character).
+    #[clap(long, default_value = "540x400", value_parser = parse_size)]
+    size: (usize, usize),
+    #[clap(long, value_enum, default_value_t = Format::Braille)]
+    format: Format,
+    /// Output file; braille and ANSI go to stdout when omitted.
+    #[clap(long, required_if_eq_any([("format", "png"), ("format", "svg")]))]
+    out: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Png,
+    Svg,
+    Braille,
+    Ansi,
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected key=value, got `{s}`"))
+}
+
+fn parse_size(s: &str) -> Result<(usize, usize), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{s}`"))
+}
+
+fn load_config(args: &Cli) -> Config {
+    match &args.config {
+        Some(path) => Config::from_file(path).expect("Could not load config"),
+        None => Config::default_path()
+            .filter(|path| path.exists())
+            .map(|path| Config::from_file(path).expect("Could not load config"))
+            .unwrap_or_default(),
+    }
+}
+
+type MakeSource = Box<dyn Fn() -> Box<dyn TileSource + Send>>;
+
+// Flags override the config file, which overrides the built-in tile server.
+// Sources of remote tiles share one disk cache.
+fn make_source(args: &Cli, config: &Config, style: &Style) -> MakeSource {
+    let tiles = match (&args.tiles, &args.tilejson, &args.tileserver) {
+        (Some(tiles), _, _) => Some(tiles),
+        (None, None, None) => config.tiles.as_ref(),
+        _ => None,
+    };
+    if let Some(path) = tiles {
+        let path = path.clone();
+        return Box::new(move || open_tiles(&path));
+    }
+
+    let tilejson = match (&args.tilejson, &args.tileserver) {
+        (Some(tilejson), _) => Some(tilejson),
+        (None, None) => config.tilejson.as_ref(),
+        (None, Some(_)) => None,
+    };
+    let (make_source, cache_key): (MakeSource, String) = match tilejson {
+        Some(location) => {
+            let ts = TileJson::load(location)
+                .and_then(TileJson::tile_source)
+                .expect("Could not load TileJSON");
+            for layer in style.missing_layers(ts.vector_layers()) {
+                warn!("Style layer {} is not provided by {}", layer, location);
+            }
+            (Box::new(move || Box::new(ts.clone())), location.clone())
+        }
+        None => {
+            let ts = tile_server(args, config).tile_source();
+            let template = ts.template().to_string();
+            (Box::new(move || Box::new(ts.clone())), template)
+        }
+    };
+
+    let cache_dir = config
+        .cache_dir
+        .clone()
+        .unwrap_or_else(diskcache::default_cache_dir)
+        .join(diskcache::cache_subdir(&cache_key));
+    let index = CacheIndex::default();
+    Box::new(move || {
+        Box::new(DiskCachedTileSource::new(&cache_dir, make_source()).with_index(index.clone()))
+    })
+}
+
+fn open_tiles(path: &Path) -> Box<dyn TileSource + Send> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("mbtiles") => {
+            Box::new(MbtilesTileSource::open(path).expect("Could not open MBTiles file"))
+        }
+        Some("pmtiles") => {
+            Box::new(PmtilesTileSource::open(path).expect("Could not open PMTiles file"))
+        }
+        _ => Box::new(DirectoryTileSource::new(path)),
+    }
+}
+
+// Tiles are loaded in the background by LOADER_THREADS workers.
+fn tile_loader(make_source: MakeSource) -> Box<dyn TileSource> {
+    let sources = (0..LOADER_THREADS).map(|_| make_source()).collect();
+    Box::new(TileLoader::new(
+        sources,
+        NonZeroUsize::new(MEMORY_CACHE_TILES).unwrap(),
+    ))
+}
+
+// Draws one frame. Tiles are loaded as they are drawn, rather than in the
+// background, so that the frame is complete.
+fn render(args: &Cli, opts: &RenderArgs, make_source: MakeSource, style: Style, board: Vec<City>) {
+    let center = pandemic::util::Coords::from_deg(args.lat, args.lon);
+    let ts = Box::new(CachedTileSource::unbounded(make_source()));
+    // Zooming in the UI stops at the same level.
+    let max_zoom = ts.zoom_range().1 + MAX_OVERZOOM;
+    if opts.zoom > max_zoom {
+        Cli::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--zoom can be at most {} with these tiles", max_zoom),
+            )
+            .exit();
+    }
+    match opts.format {
+        Format::Png => {
+            let mut renderer = ImageRenderer::new(opts.size, center, ts);
+            renderer.style = style;
+            renderer.zoom = opts.zoom;
+            renderer.projection = projection::by_name(&args.projection).unwrap();
+            renderer.draw();
+            let out = opts.out.as_ref().unwrap();
+            renderer.save(out).expect("Could not write PNG");
+        }
+        Format::Svg => {
+            let mut renderer = SvgRenderer::new(opts.size, center, ts);
+            renderer.style = style;
+            renderer.zoom = opts.zoom;
+            renderer.projection = projection::by_name(&args.projection).unwrap();
+            renderer.board = board;
+            renderer.draw();
+            let out = opts.out.as_ref().unwrap();
+            renderer.save(out).expect("Could not write SVG");
+        }
+        Format::Braille | Format::Ansi => {
+            let mut renderer = BrailleRenderer::new(opts.size, center, ts);
+            renderer.style = style;
+            renderer.zoom = opts.zoom;
+            renderer.projection = projection::by_name(&args.projection).unwrap();
+            renderer.draw();
+            let lines = match opts.format {
+                Format::Ansi => renderer.to_ansi(ColorMode::Ansi256),
+                _ => renderer.to_braille(),
+            };
+            let text = lines.join("\n") + "\n";
+            match &opts.out {
+                Some(out) => fs::write(out, text),
+                None => std::io::stdout().write_all(text.as_bytes()),
+            }
+            .expect("Could not write map");
+        }
+    }
+}
+
+fn tile_server(args: &Cli, config: &Config) -> TileServerConfig {
+    let mut server = config.tileserver.clone().unwrap_or(TileServerConfig {
+        url: tilesource::DEFAULT_TILESERVER.to_string(),
+        subdomains: Vec::new(),
+        query: Default::default(),
+    });
+    if let Some(url) = &args.tileserver {
+        server.url = url.clone();
+    }
+    if !args.subdomains.is_empty() {
+        server.subdomains = args.subdomains.clone();
+    }
+    server.query.extend(args.tile_query.iter().cloned());
+    server
+}
+
+// Lazily allocates one curses color pair per xterm-256 color the map uses.
+struct CursesPalette {
+    enabled: bool,
+    pairs: HashMap<u8, i16>,
+}
+
+impl CursesPalette {
+    fn new() -> Self {
+        let enabled = pancurses::has_colors()
+            && pancurses::start_color() == pancurses::OK
+            && pancurses::COLORS() >= 256;
+        if enabled {
+            pancurses::use_default_colors();
+        }
+        CursesPalette {
+            enabled,
+            pairs: HashMap::new(),
+        }
+    }
+
+    fn pair(&mut self, color: Rgb<u8>) -> Option<i16> {
+        let idx = pandemic::util::rgb_to_ansi256(color);
+        if let Some(pair) = self.pairs.get(&idx) {
+            return Some(*pair);
+        }
+        let pair = self.pairs.len() as i16 + 1;
+        if pair as i32 >= pancurses::COLOR_PAIRS() {
+            return None;
+        }
+        pancurses::init_pair(pair, idx as i16, -1);
+        self.pairs.insert(idx, pair);
+        Some(pair)
+    }
+
+    fn print_line(&mut self, window: &Window, line: &[(char, Option<Rgb<u8>>)]) {
+        for (c, color) in line {
+            match color.filter(|_| self.enabled).and_then(|c| self.pair(c)) {
+                Some(pair) => {
+                    let attr = pancurses::COLOR_PAIR(pair as pancurses::chtype);
+                    window.attron(attr);
+                    window.addstr(c.to_string());
+                    window.attroff(attr);
+                }
+                None => {
+                    window.addstr(c.to_string());
+                }
+            }
+        }
+    }
+}
+
+// Cities closer than this to a click, in braille dots, are picked by it.
+const SELECT_DISTANCE: f64 = 6.;
+
+// What was clicked at `p` on the map: the nearest city close enough, or else
+// the place's coordinates.
+fn select<'a>(
+    renderer: &BrailleRenderer,
+    cities: impl Iterator<Item = &'a City>,
+    p: (f64, f64),
+) -> Option<String> {
+    let city = cities
+        .filter_map(|city| {
+            let (lat, lon) = city.location?;
+            let location = pandemic::util::Coords::from_deg(lat, lon);
+            if !renderer.projection.is_visible(&location, &renderer.center) {
+                return None;
+            }
+            let (x, y) = renderer.coords_to_screen(&location);
+            Some((city, (x - p.0).hypot(y - p.1)))
+        })
+        .filter(|(_, d)| *d <= SELECT_DISTANCE)
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+    if let Some((city, _)) = city {
+        let mut s = format!("{} ({:?})", city.name, city.color);
+        let cubes: u32 = city.infections.values().sum();
+        if cubes > 0 {
+            s.push_str(&format!(", {} cubes", cubes));
+        }
+        if city.has_research_center {
+            s.push_str(", research center");
+        }
+        s.push_str(&format!(", neighbors: {}", city.neighbors.join(", ")));
+        return Some(s);
+    }
+    let c = renderer.screen_to_coords(p)?;
+    Some(format!("{:.2}, {:.2}", c.lat, c.lon))
+}
+
+// Map features closer than this to a click, in braille dots, are listed in
+// the side panel.
+const FEATURE_DISTANCE: f64 = 4.;
+
+// Width of the side panel in terminal columns, including its border.
+const PANEL_WIDTH: i32 = 32;
+
+// The side panel's lines for the features under a click: each feature's
+// layer, followed by its attributes.
+fn feature_panel(hits: &[Hit]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for hit in hits {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(match hit.id {
+            Some(id) => format!("{} #{}", hit.layer, id),
+            None => hit.layer.clone(),
+        });
+        for (key, value) in &hit.properties {
+            lines.push(format!("  {}: {}", key, value));
+        }
+    }
+    lines
 }
 
 fn main() {
     let args = Cli::parse();
     pretty_env_logger::init();
+    let config = load_config(&args);
 
     let mut game =
         pandemic::Game::from_file("cities.json").expect("Could not create game from cities.json");
@@ -76,26 +433,155 @@ fn main() {
     let center = pandemic::util::Coords::from_deg(args.lat, args.lon);
 
     // let mut renderer = pandemic::renderer::Renderer::new((281*3, 69*5), center);
-    let mut renderer = pandemic::renderer::BrailleRenderer::new((540, 400), center);
+    let style = match args.style.as_ref().or(config.style.as_ref()) {
+        Some(path) => Style::from_file(path).expect("Could not load map style"),
+        None => Style::default(),
+    };
+    let make_source = make_source(&args, &config, &style);
+    if let Some(Command::Render(opts)) = &args.command {
+        let mut board: Vec<City> = game.world.values().cloned().collect();
+        board.sort_by(|a, b| a.name.cmp(&b.name));
+        render(&args, opts, make_source, style, board);
+        return;
+    }
+
+    let ts = tile_loader(make_source);
+    let mut renderer = pandemic::renderer::BrailleRenderer::new((540, 400), center, ts);
+    renderer.style = style;
+    renderer.projection = projection::by_name(&args.projection).unwrap();
 
     let window = initscr();
+    window.keypad(true);
+    pancurses::mousemask(
+        pancurses::ALL_MOUSE_EVENTS | pancurses::REPORT_MOUSE_POSITION,
+        None,
+    );
+    // Presses and releases are reported right away instead of being held
+    // back to see whether they make a click, so that dragging feels direct.
+    pancurses::mouseinterval(0);
+    let mut palette = CursesPalette::new();
+    // getch gives up after a while, so tiles that arrive in the meantime get drawn.
+    window.timeout(POLL_INTERVAL_MS);
+    let mut redraw = true;
+    // Where the map starts on the terminal, how many columns it takes up
+    // next to the side panel, and how many rows the debug stats under it took
+    // last time.
+    let (mut map_top, mut map_cols, mut footer_rows) = (0, 0, 0);
+    // The cell the mouse was last dragged from, and whether it has moved
+    // since the button went down.
+    let mut drag_from: Option<(i32, i32)> = None;
+    let mut dragged = false;
+    let mut selected: Option<String> = None;
+    let mut panel: Vec<String> = Vec::new();
     loop {
-        window.printw(format!("Center: {:?}\n", renderer.center));
-        window.printw(format!("Zoom: {}\n", renderer.zoom));
-        window.printw(format!("Simplify: {}\n", renderer.simplify));
-        if renderer.simplify {
-            window.printw(format!("Tolerance: {:.2}\n", renderer.tolerance));
-            window.printw(format!(
-                "High Quality Simplification: {}\n",
-                renderer.high_quality
-            ));
-        }
-        renderer.draw();
-        for line in renderer.to_braille() {
-            window.printw(format!("{}\n", line));
+        if redraw {
+            renderer.poll_tiles();
+            let mut header = vec![
+                format!("Center: {:?}", renderer.center),
+                format!("Zoom: {}", renderer.zoom),
+                format!("Projection: {}", renderer.projection.name()),
+                format!("Simplify: {}", renderer.simplify),
+            ];
+            if renderer.simplify {
+                header.push(format!("Tolerance: {:.2}", renderer.tolerance));
+                header.push(format!(
+                    "High Quality Simplification: {}",
+                    renderer.high_quality
+                ));
+            }
+            if let Some(s) = &selected {
+                header.push(format!("Selected: {}", s));
+            }
+
+            // The map takes up the rest of the terminal, next to the side
+            // panel if something is selected.
+            let (rows, cols) = window.get_max_yx();
+            map_top = header.len() as i32;
+            let map_rows = (rows - map_top - footer_rows).max(1);
+            let panel_width = if panel.is_empty() {
+                0
+            } else {
+                PANEL_WIDTH.min(cols / 2)
+            };
+            map_cols = (cols - panel_width).max(1);
+            renderer.resize((map_cols as usize * 2, map_rows as usize * 4));
+
+            window.clear();
+            for (row, line) in header.iter().enumerate() {
+                window.mvprintw(row as i32, 0, line);
+            }
+            renderer.draw();
+            for (row, line) in renderer.to_braille_cells().iter().enumerate() {
+                window.mv(map_top + row as i32, 0);
+                palette.print_line(&window, line);
+            }
+            if panel_width > 0 {
+                for row in 0..map_rows {
+                    window.mv(map_top + row, map_cols);
+                    window.clrtoeol();
+                    window.addch('|');
+                    if let Some(line) = panel.get(row as usize) {
+                        window.mvaddnstr(map_top + row, map_cols + 2, line, panel_width - 2);
+                    }
+                }
+            }
+            let mut footer = Vec::new();
+            if renderer.debug {
+                footer.push(format!("Tiles: {}", renderer.tile_stats()));
+                footer.push(format!("Render: {}", renderer.stats));
+                for (layer, n) in &renderer.stats.features {
+                    footer.push(format!("  {}: {}", layer, n));
+                }
+            }
+            footer_rows = footer.len() as i32;
+            for (row, line) in footer.iter().enumerate() {
+                window.mvprintw(map_top + map_rows + row as i32, 0, line);
+            }
         }
+        redraw = true;
 
+        // The middle of a terminal cell, in braille dots.
+        let dot = |x: i32, y: i32| ((x * 2 + 1) as f64, ((y - map_top) * 4 + 2) as f64);
         match window.getch() {
+            None => {
+                redraw = renderer.poll_tiles();
+            }
+            Some(Input::KeyResize) => {
+                pancurses::resize_term(0, 0);
+            }
+            Some(Input::KeyMouse) => {
+                let Ok(event) = pancurses::getmouse() else {
+                    continue;
+                };
+                let (x, y, state) = (event.x, event.y, event.bstate);
+                // Clicks on the side panel don't reach the map, but drags
+                // that started on the map carry on over it.
+                if x >= map_cols && drag_from.is_none() {
+                    continue;
+                }
+                if state & pancurses::BUTTON1_PRESSED != 0 {
+                    drag_from = Some((x, y));
+                    dragged = false;
+                } else if state & pancurses::BUTTON4_PRESSED != 0 {
+                    renderer.zoom_at(dot(x, y), true);
+                } else if state & pancurses::BUTTON5_PRESSED != 0 {
+                    renderer.zoom_at(dot(x, y), false);
+                } else if let Some(from) = drag_from {
+                    if from != (x, y) {
+                        renderer.drag(dot(from.0, from.1), dot(x, y));
+                        dragged = true;
+                    }
+                    drag_from = Some((x, y));
+                    if state & pancurses::BUTTON1_RELEASED != 0 {
+                        drag_from = None;
+                        if !dragged {
+                            selected = select(&renderer, game.world.values(), dot(x, y));
+                            panel =
+                                feature_panel(&renderer.features_at(dot(x, y), FEATURE_DISTANCE));
+                        }
+                    }
+                }
+            }
             Some(Input::Character('a')) => {
                 renderer.zoom_in();
             }
@@ -114,6 +600,12 @@ fn main() {
             Some(Input::Character('s')) => {
                 renderer.simplify = !renderer.simplify;
             }
+            Some(Input::Character('d')) => {
+                renderer.debug = !renderer.debug;
+            }
+            Some(Input::Character('p')) => {
+                renderer.projection = projection::next(renderer.projection.as_ref());
+            }
             Some(Input::Character('l')) => {
                 renderer.pan(pandemic::renderer::Direction::RIGHT);
             }
@@ -132,8 +624,6 @@ fn main() {
             }
             _ => {}
         }
-        window.clear();
-        window.refresh();
     }
 
     // game.run();
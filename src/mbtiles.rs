@@ -0,0 +1,193 @@
+use crate::stats::TileStats;
+use crate::tile::{self, BoundingBox};
+use crate::tilesource::{self, TileSource, TileSourceError, VectorLayer};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+// https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+
+// Reads tiles out of the `tiles` table of an .mbtiles SQLite file.
+pub struct MbtilesTileSource {
+    conn: Connection,
+    zoom_range: (u32, u32),
+    stats: TileStats,
+}
+
+#[derive(Debug, Default)]
+pub struct MbtilesMetadata {
+    pub name: Option<String>,
+    pub format: Option<String>,
+    pub bounds: Option<BoundingBox>,
+    pub minzoom: Option<u32>,
+    pub maxzoom: Option<u32>,
+    pub vector_layers: Vec<VectorLayer>,
+    // Every row of the metadata table, including the ones parsed above.
+    pub raw: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct MetadataJson {
+    #[serde(default)]
+    vector_layers: Vec<VectorLayer>,
+}
+
+impl MbtilesTileSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TileSourceError> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let mut ts = MbtilesTileSource {
+            conn,
+            zoom_range: (tilesource::DEFAULT_MIN_ZOOM, tilesource::DEFAULT_MAX_ZOOM),
+            stats: TileStats::default(),
+        };
+        // The metadata table is optional, so the defaults stand in for it.
+        let metadata = ts.metadata().unwrap_or_default();
+        ts.zoom_range = (
+            metadata.minzoom.unwrap_or(ts.zoom_range.0),
+            metadata.maxzoom.unwrap_or(ts.zoom_range.1),
+        );
+        Ok(ts)
+    }
+
+    pub fn metadata(&self) -> Result<MbtilesMetadata, TileSourceError> {
+        let mut stmt = self.conn.prepare("SELECT name, value FROM metadata")?;
+        let raw: HashMap<String, String> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        // bounds are stored as "w,s,e,n"
+        let bounds = raw.get("bounds").and_then(|b| {
+            let v: Vec<f64> = b
+                .split(',')
+                .map(|x| x.trim().parse())
+                .collect::<Result<_, _>>()
+                .ok()?;
+            match v[..] {
+                [w, s, e, n] => Some(BoundingBox::new(n, s, e, w)),
+                _ => None,
+            }
+        });
+        let vector_layers = match raw.get("json") {
+            Some(json) => serde_json::from_str::<MetadataJson>(json)?.vector_layers,
+            None => Vec::new(),
+        };
+
+        Ok(MbtilesMetadata {
+            name: raw.get("name").cloned(),
+            format: raw.get("format").cloned(),
+            bounds,
+            minzoom: raw.get("minzoom").and_then(|z| z.parse().ok()),
+            maxzoom: raw.get("maxzoom").and_then(|z| z.parse().ok()),
+            vector_layers,
+            raw,
+        })
+    }
+}
+
+impl TileSource for MbtilesTileSource {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let res = self.read_tile(z, x, y);
+        self.stats.record_result(&res);
+        res
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        self.zoom_range
+    }
+
+    fn stats(&self) -> TileStats {
+        self.stats.clone()
+    }
+}
+
+impl MbtilesTileSource {
+    fn read_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let start = Instant::now();
+        // MBTiles rows follow the TMS scheme, which counts y from the south.
+        let row = (1i64 << z) - 1 - y as i64;
+        let data: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                (z as i64, x as i64, row),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match data {
+            Some(buf) => {
+                self.stats.record_fetch(buf.len(), start.elapsed());
+                tilesource::decode_tile_timed(&mut self.stats, z, x, y, &buf)
+            }
+            None => Err(TileSourceError::NotFound(z, x, y)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::geometry::Geometry;
+    use flate2::write::GzEncoder;
+    use prost::Message;
+    use std::io::Write;
+
+    #[test]
+    fn test_mbtiles_tile_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("world.mbtiles");
+
+        let mut layer = LayerBuilder::new("water");
+        layer.add_feature(
+            None,
+            &Geometry::MultiLineString(vec![vec![(0, 0), (10, 10)]]),
+            &HashMap::new(),
+        );
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&TileBuilder::new().add_layer(layer).build().encode_to_vec())
+            .unwrap();
+        let data = gz.finish().unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE metadata (name text, value text);
+             CREATE TABLE tiles (zoom_level integer, tile_column integer, tile_row integer, tile_data blob);
+             INSERT INTO metadata VALUES
+                ('name', 'test'),
+                ('format', 'pbf'),
+                ('bounds', '-180.0,-85,180,85'),
+                ('minzoom', '0'),
+                ('maxzoom', '14'),
+                ('json', '{\"vector_layers\": [{\"id\": \"water\", \"fields\": {\"class\": \"String\"}, \"minzoom\": 0, \"maxzoom\": 14}]}');",
+        )
+        .unwrap();
+        // Tile 2/1/0 (XYZ) is stored at row 3 (TMS).
+        conn.execute("INSERT INTO tiles VALUES (2, 1, 3, ?1)", [&data])
+            .unwrap();
+        drop(conn);
+
+        let mut ts = MbtilesTileSource::open(&path).unwrap();
+        assert_eq!(ts.zoom_range(), (0, 14));
+        let t = ts.get_tile(2, 1, 0).unwrap();
+        assert_eq!(t.features("water").count(), 1);
+        assert!(matches!(
+            ts.get_tile(2, 1, 3),
+            Err(TileSourceError::NotFound(2, 1, 3))
+        ));
+
+        let meta = ts.metadata().unwrap();
+        assert_eq!(meta.name.as_deref(), Some("test"));
+        assert_eq!((meta.minzoom, meta.maxzoom), (Some(0), Some(14)));
+        let b = meta.bounds.unwrap();
+        assert_eq!((b.n, b.s, b.e, b.w), (85., -85., 180., -180.));
+        assert_eq!(meta.vector_layers.len(), 1);
+        assert_eq!(meta.vector_layers[0].id, "water");
+        assert_eq!(meta.vector_layers[0].fields["class"], "String");
+    }
+}
@@ -69,9 +69,6 @@ impl Player {
     }
 
     pub fn can_give_any_card(&self) -> bool {
-        match self.class {
-            PlayerClass::Researcher => true,
-            _ => false,
-        }
+        matches!(self.class, PlayerClass::Researcher)
     }
 }
@@ -0,0 +1,544 @@
+use crate::stats::TileStats;
+use crate::tile::{self, BoundingBox};
+use crate::tilesource::{self, TileSource, TileSourceError};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::Instant;
+
+// https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+
+const HEADER_LEN: usize = 127;
+// The root directory can point at leaf directories, which can't point any further.
+const MAX_DIRECTORY_DEPTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+#[derive(Debug)]
+pub struct Header {
+    pub root_dir: (u64, u64),
+    pub metadata: (u64, u64),
+    pub leaf_dirs: (u64, u64),
+    pub tile_data: (u64, u64),
+    pub clustered: bool,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: u8,
+    pub minzoom: u8,
+    pub maxzoom: u8,
+    pub bounds: BoundingBox,
+    pub center_zoom: u8,
+    // (lat, lon)
+    pub center: (f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    // 0 for entries pointing at a leaf directory.
+    pub run_length: u32,
+}
+
+// Reads tiles from a single-file PMTiles v3 archive. Only the header and the
+// directories needed to find a tile are read from disk.
+pub struct PmtilesTileSource {
+    file: File,
+    pub header: Header,
+    root: Vec<Entry>,
+    leaves: lru::LruCache<u64, Vec<Entry>>,
+    stats: TileStats,
+}
+
+impl Compression {
+    fn from_u8(c: u8) -> Self {
+        match c {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+}
+
+impl Header {
+    pub fn parse(buf: &[u8]) -> Result<Self, TileSourceError> {
+        if buf.len() < HEADER_LEN || &buf[0..7] != b"PMTiles" {
+            return Err(invalid("missing PMTiles magic"));
+        }
+        if buf[7] != 3 {
+            return Err(invalid(&format!("unsupported spec version {}", buf[7])));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        let deg_at = |i: usize| i32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) as f64 / 1e7;
+
+        Ok(Header {
+            root_dir: (u64_at(8), u64_at(16)),
+            metadata: (u64_at(24), u64_at(32)),
+            leaf_dirs: (u64_at(40), u64_at(48)),
+            tile_data: (u64_at(56), u64_at(64)),
+            clustered: buf[96] == 1,
+            internal_compression: Compression::from_u8(buf[97]),
+            tile_compression: Compression::from_u8(buf[98]),
+            tile_type: buf[99],
+            minzoom: buf[100],
+            maxzoom: buf[101],
+            bounds: BoundingBox::new(deg_at(114), deg_at(106), deg_at(110), deg_at(102)),
+            center_zoom: buf[118],
+            center: (deg_at(123), deg_at(119)),
+        })
+    }
+}
+
+impl PmtilesTileSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TileSourceError> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; HEADER_LEN];
+        file.read_exact(&mut buf)?;
+        let header = Header::parse(&buf)?;
+
+        // Only vector tiles (1) can be drawn; 0 means the writer didn't say.
+        if header.tile_type > 1 {
+            return Err(invalid(&format!(
+                "unsupported tile type {}",
+                header.tile_type
+            )));
+        }
+
+        let mut ts = PmtilesTileSource {
+            file,
+            header,
+            root: Vec::new(),
+            leaves: lru::LruCache::new(NonZeroUsize::new(64).unwrap()),
+            stats: TileStats::default(),
+        };
+        let (offset, length) = ts.header.root_dir;
+        ts.root = ts.read_directory(offset, length)?;
+        Ok(ts)
+    }
+
+    // The archive's JSON metadata, e.g. name, attribution and vector_layers.
+    pub fn metadata(&mut self) -> Result<serde_json::Value, TileSourceError> {
+        let (offset, length) = self.header.metadata;
+        let buf = self.read_internal(offset, length)?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    pub fn find_entry(&mut self, tile_id: u64) -> Result<Option<Entry>, TileSourceError> {
+        let mut dir = self.root.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let entry = match find_entry(&dir, tile_id) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            if entry.run_length > 0 {
+                return Ok(Some(entry));
+            }
+
+            let offset = self.header.leaf_dirs.0 + entry.offset;
+            dir = match self.leaves.get(&offset) {
+                Some(leaf) => leaf.clone(),
+                None => {
+                    let leaf = self.read_directory(offset, entry.length as u64)?;
+                    self.leaves.put(offset, leaf.clone());
+                    leaf
+                }
+            };
+        }
+        Err(invalid("directories are nested too deeply"))
+    }
+
+    // Offsets and lengths come from the archive, so they're checked against
+    // the file size before anything is allocated for them.
+    fn read_range(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, TileSourceError> {
+        let file_len = self.file.metadata()?.len();
+        match offset.checked_add(length) {
+            Some(end) if end <= file_len => {}
+            _ => {
+                return Err(invalid(&format!(
+                    "range of {} bytes at {} is past the end of the file",
+                    length, offset
+                )))
+            }
+        }
+        let mut buf = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_internal(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, TileSourceError> {
+        let buf = self.read_range(offset, length)?;
+        match self.header.internal_compression {
+            Compression::None => Ok(buf),
+            Compression::Gzip => {
+                let mut raw = Vec::new();
+                flate2::read::GzDecoder::new(buf.as_slice()).read_to_end(&mut raw)?;
+                Ok(raw)
+            }
+            c => Err(invalid(&format!(
+                "unsupported internal compression {:?}",
+                c
+            ))),
+        }
+    }
+
+    fn read_directory(&mut self, offset: u64, length: u64) -> Result<Vec<Entry>, TileSourceError> {
+        let buf = self.read_internal(offset, length)?;
+        parse_directory(&buf)
+    }
+}
+
+impl TileSource for PmtilesTileSource {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let res = self.read_tile(z, x, y);
+        self.stats.record_result(&res);
+        res
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        (self.header.minzoom as u32, self.header.maxzoom as u32)
+    }
+
+    fn stats(&self) -> TileStats {
+        self.stats.clone()
+    }
+}
+
+impl PmtilesTileSource {
+    fn read_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let start = Instant::now();
+        let n = 1i64 << z;
+        if z > 31 || x < 0 || y < 0 || x as i64 >= n || y as i64 >= n {
+            return Err(TileSourceError::NotFound(z, x, y));
+        }
+        let entry = match self.find_entry(zxy_to_tile_id(z as u8, x as u32, y as u32))? {
+            Some(entry) => entry,
+            None => return Err(TileSourceError::NotFound(z, x, y)),
+        };
+
+        let offset = self
+            .header
+            .tile_data
+            .0
+            .checked_add(entry.offset)
+            .ok_or_else(|| invalid("tile offset is out of range"))?;
+        let buf = self.read_range(offset, entry.length as u64)?;
+        self.stats.record_fetch(buf.len(), start.elapsed());
+        match self.header.tile_compression {
+            Compression::Brotli | Compression::Zstd => Err(invalid(&format!(
+                "unsupported tile compression {:?}",
+                self.header.tile_compression
+            ))),
+            _ => tilesource::decode_tile_timed(&mut self.stats, z, x, y, &buf),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> TileSourceError {
+    TileSourceError::InvalidArchive(reason.to_string())
+}
+
+// Tile ids count all tiles of the lower zoom levels first, then walk the
+// current zoom level along a Hilbert curve.
+pub fn zxy_to_tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let acc = ((1u64 << (2 * z as u64)) - 1) / 3;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = (1u64 << z) / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    acc + d
+}
+
+// The entry covering a tile id: the last one starting at or before it, as
+// long as its run reaches the tile. Leaf pointers cover everything up to the
+// next entry.
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let i = entries.partition_point(|e| e.tile_id <= tile_id);
+    let entry = *entries.get(i.checked_sub(1)?)?;
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length as u64 {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn parse_directory(buf: &[u8]) -> Result<Vec<Entry>, TileSourceError> {
+    let mut pos = 0;
+    let mut next = || -> Result<u64, TileSourceError> {
+        let (v, len) = read_varint(&buf[pos..]).ok_or_else(|| invalid("truncated directory"))?;
+        pos += len;
+        Ok(v)
+    };
+
+    let n = next()? as usize;
+    // Every entry takes at least four bytes.
+    if n > buf.len() {
+        return Err(invalid("directory entry count is too large"));
+    }
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0
+        };
+        n
+    ];
+
+    let mut last_id = 0u64;
+    for e in entries.iter_mut() {
+        last_id = last_id
+            .checked_add(next()?)
+            .ok_or_else(|| invalid("tile id overflow"))?;
+        e.tile_id = last_id;
+    }
+    for e in entries.iter_mut() {
+        e.run_length = next()? as u32;
+    }
+    for e in entries.iter_mut() {
+        e.length = next()? as u32;
+    }
+    for i in 0..n {
+        // 0 means "right after the previous entry"
+        let v = next()?;
+        entries[i].offset = if v == 0 && i > 0 {
+            entries[i - 1].offset + entries[i - 1].length as u64
+        } else {
+            v.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut v = 0u64;
+    for (i, b) in buf.iter().enumerate().take(10) {
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((v, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::geometry::Geometry;
+    use flate2::write::GzEncoder;
+    use prost::Message;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn encode_directory(entries: &[Entry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, entries.len() as u64);
+        let mut last_id = 0;
+        for e in entries {
+            write_varint(&mut out, e.tile_id - last_id);
+            last_id = e.tile_id;
+        }
+        for e in entries {
+            write_varint(&mut out, e.run_length as u64);
+        }
+        for e in entries {
+            write_varint(&mut out, e.length as u64);
+        }
+        for (i, e) in entries.iter().enumerate() {
+            let contiguous =
+                i > 0 && e.offset == entries[i - 1].offset + entries[i - 1].length as u64;
+            write_varint(&mut out, if contiguous { 0 } else { e.offset + 1 });
+        }
+        out
+    }
+
+    fn gzip(buf: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(buf).unwrap();
+        gz.finish().unwrap()
+    }
+
+    fn tile_with(layer_name: &str) -> Vec<u8> {
+        let mut layer = LayerBuilder::new(layer_name);
+        layer.add_feature(None, &Geometry::MultiPoint(vec![(1, 2)]), &HashMap::new());
+        gzip(&TileBuilder::new().add_layer(layer).build().encode_to_vec())
+    }
+
+    // Lays out header, root directory, metadata, leaf directory and tile data
+    // in that order, with gzip internal compression.
+    fn write_archive(root: &[Entry], leaf: &[Entry], tiles: &[u8]) -> Vec<u8> {
+        let root = gzip(&encode_directory(root));
+        let metadata = gzip(br#"{"name": "test", "vector_layers": [{"id": "water"}]}"#);
+        let leaf = gzip(&encode_directory(leaf));
+
+        let mut sections = vec![HEADER_LEN as u64];
+        for len in [root.len(), metadata.len(), leaf.len()] {
+            sections.push(sections.last().unwrap() + len as u64);
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(b"PMTiles\x03");
+        for (offset, len) in [
+            (sections[0], root.len()),
+            (sections[1], metadata.len()),
+            (sections[2], leaf.len()),
+            (sections[3], tiles.len()),
+        ] {
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&(len as u64).to_le_bytes());
+        }
+        header.extend_from_slice(&[0; 24]);
+        header.extend_from_slice(&[1, 2, 2, 1, 0, 14]);
+        for deg in [-180., -85., 180., 85.] {
+            header.extend_from_slice(&((deg * 1e7) as i32).to_le_bytes());
+        }
+        header.push(0);
+        header.extend_from_slice(&[0; 8]);
+        assert_eq!(header.len(), HEADER_LEN);
+
+        [header, root, metadata, leaf, tiles.to_vec()].concat()
+    }
+
+    #[test]
+    fn test_tile_ids() {
+        let tests = vec![
+            ((0, 0, 0), 0),
+            ((1, 0, 0), 1),
+            ((1, 0, 1), 2),
+            ((1, 1, 1), 3),
+            ((1, 1, 0), 4),
+            ((2, 0, 0), 5),
+            ((3, 7, 0), 84),
+            ((20, 0, 0), 366503875925),
+        ];
+        for ((z, x, y), id) in tests {
+            assert_eq!(zxy_to_tile_id(z, x, y), id, "tile {}/{}/{}", z, x, y);
+        }
+    }
+
+    #[test]
+    fn test_directory_roundtrip() {
+        let entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 5,
+                offset: 10,
+                length: 7,
+                run_length: 3,
+            },
+            Entry {
+                tile_id: 300,
+                offset: 2,
+                length: 8,
+                run_length: 1,
+            },
+        ];
+        assert_eq!(
+            parse_directory(&encode_directory(&entries)).unwrap(),
+            entries
+        );
+        assert_eq!(find_entry(&entries, 6), Some(entries[1]));
+        assert_eq!(find_entry(&entries, 8), None);
+        assert!(parse_directory(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_pmtiles_tile_source() {
+        let water = tile_with("water");
+        let place = tile_with("place");
+        let tiles = [water.clone(), place.clone()].concat();
+
+        // The root holds z0 and a pointer to a leaf directory with the z1 tiles,
+        // where 1/0/0 and 1/0/1 (ids 1 and 2) share the same tile contents.
+        let root = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: water.len() as u32,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 1,
+                offset: 0,
+                length: 0,
+                run_length: 0,
+            },
+        ];
+        let leaf = vec![Entry {
+            tile_id: 1,
+            offset: water.len() as u64,
+            length: place.len() as u32,
+            run_length: 2,
+        }];
+        let mut root = root;
+        let leaf_dir = gzip(&encode_directory(&leaf));
+        root[1].length = leaf_dir.len() as u32;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("world.pmtiles");
+        std::fs::write(&path, write_archive(&root, &leaf, &tiles)).unwrap();
+
+        let mut ts = PmtilesTileSource::open(&path).unwrap();
+        assert_eq!((ts.header.minzoom, ts.header.maxzoom), (0, 14));
+        assert!((ts.header.bounds.n - 85.).abs() < 1e-6);
+        assert_eq!(ts.metadata().unwrap()["name"], "test");
+
+        assert_eq!(ts.get_tile(0, 0, 0).unwrap().features("water").count(), 1);
+        assert_eq!(ts.get_tile(1, 0, 0).unwrap().features("place").count(), 1);
+        assert_eq!(ts.get_tile(1, 0, 1).unwrap().features("place").count(), 1);
+        assert!(matches!(
+            ts.get_tile(1, 1, 1),
+            Err(TileSourceError::NotFound(1, 1, 1))
+        ));
+        assert!(matches!(
+            ts.get_tile(1, -1, 0),
+            Err(TileSourceError::NotFound(1, -1, 0))
+        ));
+
+        // A corrupt entry pointing past the end of the file.
+        root[0].length = u32::MAX;
+        std::fs::write(&path, write_archive(&root, &leaf, &tiles)).unwrap();
+        let mut ts = PmtilesTileSource::open(&path).unwrap();
+        assert!(matches!(
+            ts.get_tile(0, 0, 0),
+            Err(TileSourceError::InvalidArchive(_))
+        ));
+    }
+}
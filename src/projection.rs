@@ -0,0 +1,428 @@
+use crate::util::Coords;
+use std::f64::consts::PI;
+
+// Names accepted by `by_name`, in the order the UI cycles through them.
+pub const NAMES: [&str; 4] = ["mercator", "equirectangular", "robinson", "orthographic"];
+
+// Vector tiles don't reach further north or south than this.
+pub const MAX_TILE_LAT: f64 = 85.0511;
+
+// Maps lon/lat onto a plane where x points right, y points down, (0, 0) is
+// where the map is centered and the equator is 1 long, so all projections
+// share the same scale as Web Mercator tiles.
+pub trait Projection {
+    fn name(&self) -> &'static str;
+    // Places the projection can't show (the far side of a globe) are pulled
+    // onto the edge of the map.
+    fn project(&self, c: &Coords, center: &Coords) -> (f64, f64);
+    // None for points off the map.
+    fn unproject(&self, p: (f64, f64), center: &Coords) -> Option<Coords>;
+
+    fn is_visible(&self, c: &Coords, center: &Coords) -> bool {
+        true
+    }
+    // Web Mercator is how tiles are cut, so tile geometry needs no
+    // reprojecting and is drawn tile by tile.
+    fn is_web_mercator(&self) -> bool {
+        false
+    }
+    // How far north or south the center can be panned.
+    fn max_lat(&self) -> f64 {
+        90.
+    }
+    // How far apart copies of the world are on the plane, for maps that repeat
+    // east and west. None for a globe.
+    fn world_width(&self) -> Option<f64> {
+        Some(1.)
+    }
+
+    // Screen position of `c` on a screen of `size` pixels centered on
+    // `center`, with the equator `scale` pixels long.
+    fn coords_to_screen(
+        &self,
+        c: &Coords,
+        center: &Coords,
+        scale: f64,
+        size: (usize, usize),
+    ) -> (f64, f64) {
+        let (x, y) = self.project(c, center);
+        let (cx, cy) = self.project(center, center);
+        (
+            (x - cx) * scale + size.0 as f64 / 2.,
+            (y - cy) * scale + size.1 as f64 / 2.,
+        )
+    }
+
+    // Like `coords_to_screen`, but in whichever copy of the world is closest
+    // to the middle of the screen.
+    fn coords_to_nearest_screen(
+        &self,
+        c: &Coords,
+        center: &Coords,
+        scale: f64,
+        size: (usize, usize),
+    ) -> (f64, f64) {
+        let (x, y) = self.coords_to_screen(c, center, scale, size);
+        match self.world_width() {
+            Some(w) => {
+                let w = w * scale;
+                (x - ((x - size.0 as f64 / 2.) / w).round() * w, y)
+            }
+            None => (x, y),
+        }
+    }
+
+    // How far right of the center's copy of the world copy `world` is drawn.
+    fn world_offset(&self, world: i32, scale: f64) -> f64 {
+        self.world_width().map_or(0., |w| world as f64 * w * scale)
+    }
+
+    // Screen positions of a polygon ring.
+    fn ring_to_screen(
+        &self,
+        ring: &[Coords],
+        center: &Coords,
+        scale: f64,
+        size: (usize, usize),
+    ) -> Vec<(f64, f64)> {
+        ring.iter()
+            .map(|c| self.coords_to_screen(c, center, scale, size))
+            .collect()
+    }
+
+    // Where a screen position is, and in which copy of the world.
+    fn screen_to_coords(
+        &self,
+        p: (f64, f64),
+        center: &Coords,
+        scale: f64,
+        size: (usize, usize),
+    ) -> Option<(Coords, i32)> {
+        let (cx, cy) = self.project(center, center);
+        let x = (p.0 - size.0 as f64 / 2.) / scale + cx;
+        let y = (p.1 - size.1 as f64 / 2.) / scale + cy;
+        let (x, world) = match self.world_width() {
+            Some(w) => {
+                let world = (x / w).round();
+                (x - world * w, world as i32)
+            }
+            None => (x, 0),
+        };
+        Some((self.unproject((x, y), center)?, world))
+    }
+}
+
+pub fn by_name(name: &str) -> Option<Box<dyn Projection>> {
+    match name {
+        "mercator" => Some(Box::new(WebMercator)),
+        "equirectangular" => Some(Box::new(Equirectangular)),
+        "robinson" => Some(Box::new(Robinson)),
+        "orthographic" => Some(Box::new(Orthographic)),
+        _ => None,
+    }
+}
+
+// The projection after `p` in NAMES, wrapping around.
+pub fn next(p: &dyn Projection) -> Box<dyn Projection> {
+    let i = NAMES.iter().position(|n| *n == p.name()).unwrap_or(0);
+    by_name(NAMES[(i + 1) % NAMES.len()]).unwrap()
+}
+
+pub struct WebMercator;
+
+impl Projection for WebMercator {
+    fn name(&self) -> &'static str {
+        "mercator"
+    }
+    fn project(&self, c: &Coords, center: &Coords) -> (f64, f64) {
+        (c.lon / 360., -c.lat.to_radians().tan().asinh() / (2. * PI))
+    }
+    fn unproject(&self, p: (f64, f64), center: &Coords) -> Option<Coords> {
+        let lat = (-p.1 * 2. * PI).sinh().atan().to_degrees();
+        (p.0.abs() <= 0.5).then(|| Coords::from_deg(lat, p.0 * 360.))
+    }
+    fn is_web_mercator(&self) -> bool {
+        true
+    }
+    // The poles are infinitely far away.
+    fn max_lat(&self) -> f64 {
+        80.
+    }
+}
+
+pub struct Equirectangular;
+
+impl Projection for Equirectangular {
+    fn name(&self) -> &'static str {
+        "equirectangular"
+    }
+    fn project(&self, c: &Coords, center: &Coords) -> (f64, f64) {
+        (c.lon / 360., -c.lat / 360.)
+    }
+    fn unproject(&self, p: (f64, f64), center: &Coords) -> Option<Coords> {
+        let c = Coords::from_deg(-p.1 * 360., p.0 * 360.);
+        (c.lat.abs() <= 90. && c.lon.abs() <= 180.).then_some(c)
+    }
+}
+
+// https://en.wikipedia.org/wiki/Robinson_projection, interpolated linearly
+// between the table's rows every 5 degrees of latitude.
+pub struct Robinson;
+
+const ROBINSON_X: [f64; 19] = [
+    1.0000, 0.9986, 0.9954, 0.9900, 0.9822, 0.9730, 0.9600, 0.9427, 0.9216, 0.8962, 0.8679, 0.8350,
+    0.7986, 0.7597, 0.7186, 0.6732, 0.6213, 0.5722, 0.5322,
+];
+const ROBINSON_Y: [f64; 19] = [
+    0.0000, 0.0620, 0.1240, 0.1860, 0.2480, 0.3100, 0.3720, 0.4340, 0.4958, 0.5571, 0.6176, 0.6769,
+    0.7346, 0.7903, 0.8435, 0.8936, 0.9394, 0.9761, 1.0000,
+];
+// Earth radius for an equator of length 1.
+const ROBINSON_R: f64 = 1. / (0.8487 * 2. * PI);
+
+fn interpolate(table: &[f64; 19], lat: f64) -> f64 {
+    let i = (lat.abs() / 5.).min(17.999);
+    let (k, f) = (i.floor() as usize, i.fract());
+    table[k] + (table[k + 1] - table[k]) * f
+}
+
+impl Projection for Robinson {
+    fn name(&self) -> &'static str {
+        "robinson"
+    }
+    fn project(&self, c: &Coords, center: &Coords) -> (f64, f64) {
+        let lat = c.lat.clamp(-90., 90.);
+        (
+            0.8487 * ROBINSON_R * interpolate(&ROBINSON_X, lat) * c.lon.to_radians(),
+            -1.3523 * ROBINSON_R * interpolate(&ROBINSON_Y, lat) * lat.signum(),
+        )
+    }
+    fn unproject(&self, p: (f64, f64), center: &Coords) -> Option<Coords> {
+        let y = p.1.abs() / (1.3523 * ROBINSON_R);
+        if y > 1. {
+            return None;
+        }
+        let k = ROBINSON_Y
+            .iter()
+            .rposition(|v| *v <= y)
+            .unwrap_or(0)
+            .min(17);
+        let f = (y - ROBINSON_Y[k]) / (ROBINSON_Y[k + 1] - ROBINSON_Y[k]);
+        let lat = -(k as f64 + f) * 5. * p.1.signum();
+        let lon = p.0 / (0.8487 * ROBINSON_R * interpolate(&ROBINSON_X, lat));
+        (lon.abs() <= PI).then(|| Coords::from_deg(lat, lon.to_degrees()))
+    }
+}
+
+// A globe seen from far away above the center.
+// https://en.wikipedia.org/wiki/Orthographic_map_projection
+pub struct Orthographic;
+
+// Globe radius for an equator of length 1.
+const ORTHOGRAPHIC_R: f64 = 1. / (2. * PI);
+
+impl Projection for Orthographic {
+    fn name(&self) -> &'static str {
+        "orthographic"
+    }
+    fn project(&self, c: &Coords, center: &Coords) -> (f64, f64) {
+        let (phi, phi0) = (c.lat.to_radians(), center.lat.to_radians());
+        let dlambda = (c.lon - center.lon).to_radians();
+        let x = ORTHOGRAPHIC_R * phi.cos() * dlambda.sin();
+        let y = -ORTHOGRAPHIC_R * (phi0.cos() * phi.sin() - phi0.sin() * phi.cos() * dlambda.cos());
+        let r = x.hypot(y);
+        if self.is_visible(c, center) || r == 0. {
+            (x, y)
+        } else {
+            (x / r * ORTHOGRAPHIC_R, y / r * ORTHOGRAPHIC_R)
+        }
+    }
+    fn unproject(&self, p: (f64, f64), center: &Coords) -> Option<Coords> {
+        let (x, y) = (p.0, -p.1);
+        let rho = x.hypot(y);
+        if rho > ORTHOGRAPHIC_R {
+            return None;
+        }
+        if rho == 0. {
+            return Some(*center);
+        }
+        let phi0 = center.lat.to_radians();
+        let c = (rho / ORTHOGRAPHIC_R).asin();
+        let lat = (c.cos() * phi0.sin() + y * c.sin() * phi0.cos() / rho).asin();
+        let dlambda = (x * c.sin()).atan2(rho * c.cos() * phi0.cos() - y * c.sin() * phi0.sin());
+        let lon = (center.lon + dlambda.to_degrees() + 180.).rem_euclid(360.) - 180.;
+        Some(Coords::from_deg(lat.to_degrees(), lon))
+    }
+    // Rings are cut to the near side of the globe, and where they go around
+    // the back they follow the globe's edge instead, taking the shorter way.
+    fn ring_to_screen(
+        &self,
+        ring: &[Coords],
+        center: &Coords,
+        scale: f64,
+        size: (usize, usize),
+    ) -> Vec<(f64, f64)> {
+        // Unit vectors with x to the right, y down and z towards the viewer.
+        let (sin0, cos0) = center.lat.to_radians().sin_cos();
+        let points: Vec<[f64; 3]> = ring
+            .iter()
+            .map(|c| {
+                let (sin, cos) = c.lat.to_radians().sin_cos();
+                let dlambda = (c.lon - center.lon).to_radians();
+                [
+                    cos * dlambda.sin(),
+                    -(cos0 * sin - sin0 * cos * dlambda.cos()),
+                    sin0 * sin + cos0 * cos * dlambda.cos(),
+                ]
+            })
+            .collect();
+
+        // Sutherland-Hodgman against z >= 0, remembering which points are on
+        // the edge.
+        let mut clipped: Vec<((f64, f64), bool)> = Vec::new();
+        for (i, q) in points.iter().enumerate() {
+            let p = points[(i + points.len() - 1) % points.len()];
+            let crossing = || {
+                let t = p[2] / (p[2] - q[2]);
+                let (x, y) = (p[0] + t * (q[0] - p[0]), p[1] + t * (q[1] - p[1]));
+                let r = x.hypot(y);
+                ((x / r, y / r), true)
+            };
+            if q[2] >= 0. {
+                if p[2] < 0. {
+                    clipped.push(crossing());
+                }
+                clipped.push(((q[0], q[1]), false));
+            } else if p[2] >= 0. {
+                clipped.push(crossing());
+            }
+        }
+
+        // Two edge points in a row are where the ring went behind the globe
+        // and came back.
+        let mut out = Vec::with_capacity(clipped.len());
+        for (i, &(p, on_edge)) in clipped.iter().enumerate() {
+            out.push(p);
+            let (q, next_on_edge) = clipped[(i + 1) % clipped.len()];
+            if on_edge && next_on_edge {
+                let (a, b) = (p.1.atan2(p.0), q.1.atan2(q.0));
+                let d = (b - a + PI).rem_euclid(2. * PI) - PI;
+                let n = (d.abs() / (PI / 32.)).ceil() as i32;
+                out.extend((1..n).map(|k| {
+                    let e = a + d * k as f64 / n as f64;
+                    (e.cos(), e.sin())
+                }));
+            }
+        }
+        out.into_iter()
+            .map(|(x, y)| {
+                (
+                    x * ORTHOGRAPHIC_R * scale + size.0 as f64 / 2.,
+                    y * ORTHOGRAPHIC_R * scale + size.1 as f64 / 2.,
+                )
+            })
+            .collect()
+    }
+    fn world_width(&self) -> Option<f64> {
+        None
+    }
+    // Whether `c` is on the near side of the globe.
+    fn is_visible(&self, c: &Coords, center: &Coords) -> bool {
+        let (phi, phi0) = (c.lat.to_radians(), center.lat.to_radians());
+        let dlambda = (c.lon - center.lon).to_radians();
+        phi0.sin() * phi.sin() + phi0.cos() * phi.cos() * dlambda.cos() >= 0.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips() {
+        let center = Coords::from_deg(30., -40.);
+        for name in NAMES {
+            let p = by_name(name).unwrap();
+            for c in [
+                Coords::from_deg(0., 0.),
+                Coords::from_deg(48.85, 2.35),
+                Coords::from_deg(-33.9, 151.2),
+                Coords::from_deg(40.71, -74.),
+                Coords::from_deg(-70., -100.),
+            ] {
+                if !p.is_visible(&c, &center) {
+                    continue;
+                }
+                let (back, world) = p
+                    .screen_to_coords(
+                        p.coords_to_screen(&c, &center, 1000., (300, 200)),
+                        &center,
+                        1000.,
+                        (300, 200),
+                    )
+                    .unwrap_or_else(|| panic!("{} lost {:?}", name, c));
+                assert!(
+                    (back.lat - c.lat).abs() < 1e-6
+                        && (back.lon - c.lon).abs() < 1e-6
+                        && world == 0,
+                    "{}: {:?} came back as {:?}",
+                    name,
+                    c,
+                    back
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_projections() {
+        let center = Coords::from_deg(0., 0.);
+        // The equator is 1 long in every projection.
+        for name in NAMES.iter().take(3) {
+            let p = by_name(name).unwrap();
+            let (w, _) = p.project(&Coords::from_deg(0., -180.), &center);
+            let (e, _) = p.project(&Coords::from_deg(0., 180.), &center);
+            assert!((e - w - 1.).abs() < 1e-9, "{}", name);
+        }
+
+        // The center of the screen shows the center.
+        let c = Coords::from_deg(30., -40.);
+        assert_eq!(
+            Orthographic.coords_to_screen(&c, &c, 512., (100, 80)),
+            (50., 40.)
+        );
+
+        // The far side of the globe is pulled onto its edge, and nothing is
+        // shown outside the globe.
+        let sydney = Coords::from_deg(-33.9, 151.2);
+        assert!(!Orthographic.is_visible(&sydney, &c));
+        let (x, y) = Orthographic.project(&sydney, &c);
+        assert!((x.hypot(y) - ORTHOGRAPHIC_R).abs() < 1e-9);
+        assert_eq!(Orthographic.unproject((ORTHOGRAPHIC_R, 0.01), &c), None);
+
+        // Across the antimeridian is the next copy of the world, where flat
+        // maps repeat.
+        let fiji = Coords::from_deg(-18., 178.);
+        let (x, _) =
+            WebMercator.coords_to_screen(&Coords::from_deg(-18., -178.), &fiji, 360., (100, 80));
+        assert_eq!(x.round(), -306.);
+        let (x, _) = WebMercator.coords_to_nearest_screen(
+            &Coords::from_deg(-18., -178.),
+            &fiji,
+            360.,
+            (100, 80),
+        );
+        assert_eq!(x.round(), 54.);
+        let (c, world) = Equirectangular
+            .screen_to_coords((54., 40.), &fiji, 360., (100, 80))
+            .unwrap();
+        assert_eq!((c.lon.round(), world), (-178., 1));
+        let (c, world) = Orthographic
+            .screen_to_coords((54., 40.), &fiji, 360., (100, 80))
+            .unwrap();
+        assert_eq!((c.lon.round(), world), (-178., 0));
+
+        assert_eq!(by_name("gall-peters").map(|p| p.name()), None);
+        assert_eq!(next(&Orthographic).name(), "mercator");
+    }
+}
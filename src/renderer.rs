@@ -1,5 +1,9 @@
-use crate::tile::{self, BoundingBox, GeometryCommand, Tile};
-use crate::tilesource::{CachedTileSource, TileServerSource, TileSource};
+use crate::geometry::{self, Geometry};
+use crate::projection::{self, Projection, WebMercator};
+use crate::stats::{self, RenderStats, TileStats};
+use crate::style::{DrawKind, LayerStyle, Style};
+use crate::tile::{self, BoundingBox, Feature, PropertyValue, Tile};
+use crate::tilesource::{TileSource, TileSourceError};
 use crate::util;
 use crate::util::Coords;
 use crate::vector_tile;
@@ -9,31 +13,28 @@ use bresenham;
 use image::{GenericImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
 use rand::{thread_rng, Rng};
 use simplify_polyline as sp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::f64::consts::PI;
+use std::path::Path;
 
-const MIN_ZOOM: u32 = 0;
-const MAX_ZOOM: u32 = 14;
-
-macro_rules! hashmap {
-    ($( $key: expr => $val: expr ),*) => {{
-         let mut map = ::std::collections::HashMap::new();
-         $( map.insert($key, $val); )*
-         map
-    }}
-}
+// Zoom levels past the tile source's max zoom that are drawn by scaling up
+// its most detailed tiles.
+pub const MAX_OVERZOOM: u32 = 6;
 
 pub struct ImageRenderer {
     width: usize,
     height: usize,
     pub center: Coords,
     pub zoom: u32,
+    pub projection: Box<dyn Projection>,
     tilesource: Box<dyn TileSource>,
     img: RgbImage,
     rel_zoom: f64,
     pub simplify: bool,
     pub tolerance: f64,
     pub high_quality: bool,
+    pub style: Style,
+    pub stats: RenderStats,
 }
 
 pub struct BrailleRenderer {
@@ -41,12 +42,54 @@ pub struct BrailleRenderer {
     height: usize,
     pub center: Coords,
     pub zoom: u32,
+    pub projection: Box<dyn Projection>,
     tilesource: Box<dyn TileSource>,
-    buf: Vec<Vec<bool>>,
+    buf: Vec<Vec<Option<Rgb<u8>>>>,
+    labels: Vec<Label>,
     rel_zoom: f64,
     pub simplify: bool,
     pub tolerance: f64,
     pub high_quality: bool,
+    pub style: Style,
+    pub stats: RenderStats,
+    // Outline tiles and label them with their z/x/y.
+    pub debug: bool,
+}
+
+/// Escape sequences used when printing colored braille to a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Ansi256,
+    TrueColor,
+}
+
+// A piece of text anchored to a braille cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub text: String,
+    pub col: i32,
+    pub row: i32,
+    pub color: Rgb<u8>,
+    priority: (usize, i64),
+}
+
+// Labels with the same text closer than this (in cells) are considered copies
+// of each other, which happens when a place lies in the buffer of two tiles.
+const LABEL_DEDUP_DISTANCE: i32 = 8;
+
+// Color of the tile outlines and z/x/y labels drawn in debug mode.
+const DEBUG_COLOR: Rgb<u8> = Rgb([255, 0, 255]);
+
+// A feature under or near a point on screen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub layer: String,
+    pub id: Option<u64>,
+    // Sorted by key.
+    pub properties: Vec<(String, PropertyValue)>,
+    // How far the feature is from the point, in pixels (dots for braille).
+    // Zero when the point lies inside a filled polygon.
+    pub distance: f64,
 }
 
 pub enum Direction {
@@ -57,7 +100,7 @@ pub enum Direction {
 }
 
 pub trait Renderer {
-    fn new(res: (usize, usize), center: Coords) -> Self;
+    fn new(res: (usize, usize), center: Coords, tilesource: Box<dyn TileSource>) -> Self;
     // fn width(&self) -> i32;
     // fn height(&self) -> i32;
 
@@ -70,96 +113,273 @@ pub trait Renderer {
     fn draw(&mut self);
     fn draw_tile(&mut self, t: &tile::Tile);
     fn draw_line(&mut self, t: &Tile, p: (i32, i32), q: (i32, i32), extent: u32, color: Rgb<u8>);
+    fn fill_polygon(&mut self, t: &Tile, rings: &[Vec<sp::Point<f32>>], color: Rgb<u8>);
     fn visible_tiles(&mut self) -> Vec<tile::Tile>;
+    // Whether tiles arrived in the background since the last call, meaning
+    // it's worth drawing again.
+    fn poll_tiles(&mut self) -> bool;
+    fn tile_stats(&self) -> TileStats;
+    // The features drawn within `radius` of a point on screen, nearest first.
+    fn features_at(&mut self, p: (f64, f64), radius: f64) -> Vec<Hit>;
+}
+
+// The part of the world a renderer shows, and on how big a screen.
+pub struct View<'a> {
+    pub projection: &'a dyn Projection,
+    pub center: &'a Coords,
+    pub zoom: u32,
+    // Size of a tile on screen, relative to 256 pixels.
+    pub rel_zoom: f64,
+    pub size: (usize, usize),
+}
+
+// Where tiles and places end up on a renderer's screen. Drawing and
+// hit-testing both place geometry through these.
+pub trait ScreenSpace {
+    fn view(&self) -> View<'_>;
+
+    // Size of the whole world on screen.
+    fn scale(&self) -> f64 {
+        let v = self.view();
+        256. * v.rel_zoom * 2f64.powf(v.zoom as f64)
+    }
+
+    // Where a place ends up on screen, in the copy of the world nearest the
+    // middle.
+    fn coords_to_screen(&self, c: &Coords) -> (f64, f64) {
+        let v = self.view();
+        v.projection
+            .coords_to_nearest_screen(c, v.center, self.scale(), v.size)
+    }
+
+    // The place at a screen position, if the map shows one there.
+    fn screen_to_coords(&self, p: (f64, f64)) -> Option<Coords> {
+        let v = self.view();
+        v.projection
+            .screen_to_coords(p, v.center, self.scale(), v.size)
+            .map(|(c, _)| c)
+    }
+
+    // Where a point of `t` ends up on screen, in the tile's copy of the world.
+    fn tile_coords_to_screen(&self, t: &Tile, c: &Coords) -> (f64, f64) {
+        let v = self.view();
+        let scale = self.scale();
+        let (x, y) = v.projection.coords_to_screen(c, v.center, scale, v.size);
+        (x + v.projection.world_offset(t.world, scale), y)
+    }
+
+    // Outside Web Mercator, a feature's geometry is cut up before it's
+    // reprojected (see cut_for_projection).
+    fn cut_for_view(&self, t: &Tile, geometry: Geometry, extent: u32, fill: bool) -> Geometry {
+        let v = self.view();
+        if v.projection.is_web_mercator() {
+            geometry
+        } else {
+            cut_for_projection(geometry, t, extent, fill, v.projection, v.center)
+        }
+    }
+
+    // A feature's geometry on screen, placed the way draw_tile places it.
+    fn geometry_to_screen(
+        &self,
+        t: &Tile,
+        geometry: Geometry,
+        extent: u32,
+        fill: bool,
+    ) -> Geometry<(f64, f64)> {
+        let point = |p: sp::Point<f32>| (p.x as f64, p.y as f64);
+        match self.cut_for_view(t, geometry, extent, fill) {
+            Geometry::MultiPolygon(polygons) if fill => Geometry::MultiPolygon(
+                polygons
+                    .iter()
+                    .map(|rings| {
+                        rings
+                            .iter()
+                            .map(|ring| {
+                                self.ring_to_screen_space(t, ring, extent)
+                                    .into_iter()
+                                    .map(point)
+                                    .collect()
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            ),
+            geometry => geometry.map(|p| point(self.tile_point_to_screen_space(t, p, extent))),
+        }
+    }
+
+    fn ring_to_screen_space(
+        &self,
+        t: &Tile,
+        ring: &[(i32, i32)],
+        extent: u32,
+    ) -> Vec<sp::Point<f32>> {
+        let v = self.view();
+        if v.projection.is_web_mercator() {
+            return ring
+                .iter()
+                .map(|p| self.tile_point_to_screen_space(t, *p, extent))
+                .collect();
+        }
+        let ring: Vec<Coords> = ring
+            .iter()
+            .map(|p| t.tile_point_to_coords(*p, extent))
+            .collect();
+        let scale = self.scale();
+        let dx = v.projection.world_offset(t.world, scale);
+        v.projection
+            .ring_to_screen(&ring, v.center, scale, v.size)
+            .into_iter()
+            .map(|(x, y)| sp::Point {
+                x: (x + dx) as f32,
+                y: y as f32,
+            })
+            .collect()
+    }
+
+    fn tile_point_to_screen_space(&self, t: &Tile, p: (i32, i32), extent: u32) -> sp::Point<f32> {
+        let v = self.view();
+        if !v.projection.is_web_mercator() {
+            let (x, y) = self.tile_coords_to_screen(t, &t.tile_point_to_coords(p, extent));
+            return sp::Point {
+                x: x as f32,
+                y: y as f32,
+            };
+        }
+        let base_size = 256.;
+        let scale = base_size / extent as f32 * v.rel_zoom as f32;
+        // Overzoomed tiles are blown up so that their quadrant fills the tile.
+        let overzoom = (1u32 << t.overzoom) as f32;
+        let (qx, qy) = t.quadrant();
+        sp::Point {
+            x: t.screenpos.0 as f32 + (p.0 as f32 * overzoom - (qx as u32 * extent) as f32) * scale,
+            y: t.screenpos.1 as f32 + (p.1 as f32 * overzoom - (qy as u32 * extent) as f32) * scale,
+        }
+    }
+
+    // The part of the screen covered by a tile, so fills don't spill into the
+    // buffer area that neighboring tiles draw as well.
+    fn tile_clip_rect(&self, t: &Tile) -> ((i32, i32), (i32, i32)) {
+        let v = self.view();
+        let (width, height) = (v.size.0 as i32, v.size.1 as i32);
+        if !v.projection.is_web_mercator() {
+            // Reprojected geometry was already cut to the tile.
+            return ((0, 0), (width, height));
+        }
+        let tile_screen_size = (256. * v.rel_zoom).round() as i32;
+        (
+            (t.screenpos.0.max(0), t.screenpos.1.max(0)),
+            (
+                (t.screenpos.0 + tile_screen_size).min(width),
+                (t.screenpos.1 + tile_screen_size).min(height),
+            ),
+        )
+    }
 }
 
 impl Renderer for BrailleRenderer {
-    fn new(res: (usize, usize), center: Coords) -> Self {
+    fn new(res: (usize, usize), center: Coords, tilesource: Box<dyn TileSource>) -> Self {
         BrailleRenderer {
             width: res.0,
             height: res.1,
-            center,
+            center: Coords::from_deg(center.lat, util::normalize_lon(center.lon)),
             zoom: 0,
-            tilesource: Box::new(CachedTileSource::unbounded(TileServerSource::new())),
-            buf: vec![vec![false; res.1]; res.0],
+            projection: Box::new(WebMercator),
+            tilesource,
+            buf: vec![vec![None; res.1]; res.0],
+            labels: Vec::new(),
             rel_zoom: 2.,
             simplify: false,
             tolerance: 1.,
             high_quality: false,
+            style: Style::default(),
+            stats: RenderStats::default(),
+            debug: false,
         }
     }
     fn zoom_in(&mut self) {
-        // self.zoom = std::cmp::min(self.zoom + 1, MAX_ZOOM);
-
         if (self.rel_zoom + 0.2).floor() != self.rel_zoom.floor() {
+            if self.zoom >= self.tilesource.zoom_range().1 + MAX_OVERZOOM {
+                return;
+            }
             self.rel_zoom = self.rel_zoom.floor();
             self.zoom += 1;
         } else {
-        self.rel_zoom += 0.2;
+            self.rel_zoom += 0.2;
         }
     }
     fn zoom_out(&mut self) {
-        // if self.zoom > std::cmp::max(0, MIN_ZOOM) {
-        //     self.zoom -= 1;
-        // }
-
-        // self.rel_zoom -= 0.2;
+        // The reverse of zoom_in: from the bottom of a zoom level to the top of
+        // the one below.
+        if self.rel_zoom.fract() < 0.1 {
+            if self.zoom <= self.tilesource.zoom_range().0 {
+                return;
+            }
+            self.rel_zoom = self.rel_zoom.floor() + 0.8;
+            self.zoom -= 1;
+        } else {
+            self.rel_zoom = (self.rel_zoom - 0.2).max(self.rel_zoom.floor());
+        }
     }
     fn pan(&mut self, d: Direction) {
-        let scaler =  2f64.powf(self.zoom as f64);
+        let scaler = 2f64.powf(self.zoom as f64);
         match d {
             Direction::RIGHT => {
-                self.center.lon += 5. / scaler;
+                self.center.lon = util::normalize_lon(self.center.lon + 5. / scaler);
             }
             Direction::LEFT => {
-                self.center.lon -= 5. / scaler;
+                self.center.lon = util::normalize_lon(self.center.lon - 5. / scaler);
             }
             Direction::UP => {
-                self.center.lat = (self.center.lat + 5.  / scaler ).min(80.);
+                self.center.lat = (self.center.lat + 5. / scaler).min(self.projection.max_lat());
             }
             Direction::DOWN => {
-                self.center.lat = (self.center.lat - 5.  / scaler).max(-80.);
+                self.center.lat = (self.center.lat - 5. / scaler).max(-self.projection.max_lat());
             }
         };
     }
 
     fn clear_buf(&mut self) {
         for line in &mut self.buf {
-            line.fill(false);
+            line.fill(None);
         }
     }
 
     fn draw(&mut self) {
         self.clear_buf();
+        self.labels.clear();
+        self.stats = RenderStats::default();
+
+        let (tiles, draw_time) = stats::timed(|| {
+            let tiles: Vec<tile::Tile> = self.visible_tiles();
+            info!("There are {} visible tiles", tiles.len());
 
-        let mut tiles: Vec<tile::Tile> = self.visible_tiles();
-        info!("There are {} visible tiles", tiles.len());
+            for t in &tiles {
+                self.draw_tile(t);
+                // info!("Screen position of tile: {:?}", self.screen_position(&t));
+            }
+            tiles
+        });
+        self.stats.draw_time = draw_time;
 
-        for t in &tiles {
-            self.draw_tile(&t);
-            // info!("Screen position of tile: {:?}", self.screen_position(&t));
+        self.labels = place_labels(
+            std::mem::take(&mut self.labels),
+            (self.width / 2) as i32,
+            (self.height / 4) as i32,
+        );
+        if self.debug {
+            for t in &tiles {
+                self.draw_tile_outline(t);
+            }
         }
     }
     fn draw_tile(&mut self, t: &tile::Tile) {
         info!("bounding box: {:?}", t.bounds());
-        let layer_colors = hashmap! {
-            "aeroway" => Rgb([47, 79, 79]),
-            "boundary" => Rgb([107, 142, 35]),
-            "building" => Rgb([100, 149, 237]),
-            "housenumber" => Rgb([192, 192, 192]),
-            "landcover" => Rgb([221, 160, 221]),
-            "landuse" => Rgb([106, 90, 205]),
-            "mountain_peak" => Rgb([255, 248, 220]),
-            "park" => Rgb([255, 228, 196]),
-            "place" => Rgb([253, 150, 147]),
-            "poi" => Rgb([4, 88, 187]),
-            "transportation" => Rgb([241, 5, 37]),
-            "transportation_name" => Rgb([19, 79, 94]),
-            "water" => Rgb([176, 1, 20]),
-            "water_name" => Rgb([254, 70, 216]),
-            "waterway" => Rgb([107, 41, 12])
-        };
-
+        self.stats.tiles += 1;
+        if t.overzoom > 0 {
+            self.stats.overzoomed += 1;
+        }
         let tile_screen_size = (256. * self.rel_zoom).round() as i32;
         for i in 0..tile_screen_size {
             for j in 0..tile_screen_size {
@@ -172,71 +392,113 @@ impl Renderer for BrailleRenderer {
         }
 
         if let Some(vtile) = t.vtile.as_ref() {
-            for layer in &vtile.layers {
-                let extent = layer.extent();
-
-                let color = layer_colors
-                    .get(layer.name.as_str())
-                    .unwrap_or(&Rgb([255, 255, 255]));
-
-                info!("layer,{}", layer.name);
-
-                for feature in &layer.features {
-                    let commands = tile::Tile::parse_geometry(&feature.geometry);
+            let rules: Vec<LayerStyle> = self.style.layers_at(t.z() as u32).cloned().collect();
+            for rule in &rules {
+                let color = rule.rgb();
+
+                for layer in vtile.layers.iter().filter(|l| l.name == rule.layer) {
+                    let extent = layer.extent();
+                    if extent == 0 {
+                        warn!("Skipping layer {} with zero extent", layer.name);
+                        continue;
+                    }
 
-                    match feature.r#type() {
-                        GeomType::Unknown => {
-                            panic!("Found unknown geometry, don't know how to interpret this");
-                        }
-                        GeomType::Point => {
-                            let mut cursor = (0, 0);
-                            for c in commands {
-                                match c {
-                                    tile::GeometryCommand::MoveTo(dx, dy) => {
-                                        let nc = (cursor.0 + dx, cursor.1 + dy);
-
-                                        let p = self.tile_point_to_screen_space(t, nc, extent);
-                                        let p = (p.x.round() as i32, p.y.round() as i32);
+                    info!("layer,{}", layer.name);
+
+                    for feature in layer
+                        .features
+                        .iter()
+                        .map(|f| Feature::new(layer, f))
+                        .filter(|f| rule.matches(f))
+                    {
+                        let geometry = match feature.geometry() {
+                            Ok(geometry) => geometry,
+                            Err(e) => {
+                                self.stats.dropped += 1;
+                                warn!(
+                                    "Skipping feature {:?} of layer {}: {}",
+                                    feature.id(),
+                                    layer.name,
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+                        *self.stats.features.entry(layer.name.clone()).or_default() += 1;
+                        let geometry =
+                            self.cut_for_view(t, geometry, extent, rule.kind == DrawKind::Fill);
+
+                        match geometry {
+                            Geometry::MultiPoint(points) => {
+                                for p in points {
+                                    let p = self.tile_point_to_screen_space(t, p, extent);
+                                    let p = (p.x.round() as i32, p.y.round() as i32);
 
-                                        if self.point_within_bounds(p) {
-                                            self.buf[p.0 as usize][p.1 as usize] = true;
+                                    if self.point_within_bounds(p)
+                                        && (t.overzoom == 0
+                                            || !self.projection.is_web_mercator()
+                                            || self.point_within_tile_bounds(t, p))
+                                    {
+                                        self.buf[p.0 as usize][p.1 as usize] = Some(color);
+
+                                        if let Some(label) = &rule.label {
+                                            if let Some(text) = label.text(&feature) {
+                                                let len = text.chars().count() as i32;
+                                                self.labels.push(Label {
+                                                    text,
+                                                    col: p.0 / 2 - len / 2,
+                                                    row: p.1 / 4,
+                                                    color,
+                                                    priority: label.priority(&feature),
+                                                });
+                                            }
                                         }
-                                        cursor = nc;
                                     }
-                                    _ => {
-                                        panic!("Point geometry can only contain MoveTo commands");
-                                    }
-                                };
+                                }
                             }
-                        }
-                        GeomType::Linestring | GeomType::Polygon => {
-                            let mut lines: Vec<Vec<sp::Point<f32>>> = self
-                                .commands_to_polylines(&commands)
-                                .into_iter()
-                                .map(|line| {
-                                    line.into_iter()
-                                        .map(|p| self.tile_point_to_screen_space(t, p, extent))
-                                        .collect()
-                                })
-                                .collect();
-
-                            if self.simplify {
-                                for i in 0..lines.len() {
-                                    let before = lines[i].len();
-                                    lines[i] = sp::simplify(&lines[i], self.tolerance, false);
-                                    let after = lines[i].len();
-
-                                    info!("Simplified from {} points to {} points", before, after);
+                            Geometry::MultiPolygon(polygons) if rule.kind == DrawKind::Fill => {
+                                for rings in polygons {
+                                    let rings: Vec<Vec<sp::Point<f32>>> = rings
+                                        .iter()
+                                        .map(|ring| self.ring_to_screen_space(t, ring, extent))
+                                        .collect();
+                                    self.fill_polygon(t, &rings, color);
                                 }
                             }
+                            geometry => {
+                                let mut lines: Vec<Vec<sp::Point<f32>>> = geometry
+                                    .into_lines()
+                                    .into_iter()
+                                    .map(|line| {
+                                        line.into_iter()
+                                            .map(|p| self.tile_point_to_screen_space(t, p, extent))
+                                            .collect()
+                                    })
+                                    .collect();
+
+                                if self.simplify {
+                                    for line in lines.iter_mut() {
+                                        let before = line.len();
+                                        *line = sp::simplify(line, self.tolerance, false);
+                                        let after = line.len();
+                                        self.stats.points_before_simplify += before;
+                                        self.stats.points_after_simplify += after;
+
+                                        info!(
+                                            "Simplified from {} points to {} points",
+                                            before, after
+                                        );
+                                    }
+                                }
 
-                            lines.iter().for_each(|line| {
-                                line.iter().zip(line.iter().skip(1)).for_each(|(p, q)| {
-                                    let p = (p.x.round() as i32, p.y.round() as i32);
-                                    let q = (q.x.round() as i32, q.y.round() as i32);
-                                    self.draw_line(t, p, q, extent, *color);
+                                lines.iter().for_each(|line| {
+                                    line.iter().zip(line.iter().skip(1)).for_each(|(p, q)| {
+                                        let p = (p.x.round() as i32, p.y.round() as i32);
+                                        let q = (q.x.round() as i32, q.y.round() as i32);
+                                        self.draw_line(t, p, q, extent, color);
+                                    });
                                 });
-                            });
+                            }
                         }
                     }
                 }
@@ -258,6 +520,20 @@ impl Renderer for BrailleRenderer {
         // }
     }
     fn draw_line(&mut self, t: &Tile, p: (i32, i32), q: (i32, i32), extent: u32, color: Rgb<u8>) {
+        if t.overzoom > 0 || !self.projection.is_web_mercator() {
+            // Scaled-up lines reach far past the tile, so they're cut to it.
+            // Reprojected lines can end up anywhere, so they're cut to the
+            // screen.
+            if let Some((p, q)) = geometry::clip_segment(p, q, self.tile_clip_rect(t)) {
+                for x in bresenham::Bresenham::new(
+                    (p.0 as isize, p.1 as isize),
+                    (q.0 as isize, q.1 as isize),
+                ) {
+                    self.buf[x.0 as usize][x.1 as usize] = Some(color);
+                }
+            }
+            return;
+        }
         if self.point_within_bounds(p)
             && self.point_within_bounds(q)
             && (self.point_within_tile_bounds(t, p) || self.point_within_tile_bounds(t, q))
@@ -266,89 +542,87 @@ impl Renderer for BrailleRenderer {
                 (p.0 as isize, p.1 as isize),
                 (q.0 as isize, q.1 as isize),
             ) {
-                self.buf[x.0 as usize][x.1 as usize] = true;
+                self.buf[x.0 as usize][x.1 as usize] = Some(color);
+            }
+        }
+    }
+    fn fill_polygon(&mut self, t: &Tile, rings: &[Vec<sp::Point<f32>>], color: Rgb<u8>) {
+        for (y, x0, x1) in geometry::scanline_fill(rings, self.tile_clip_rect(t)) {
+            for x in x0..=x1 {
+                self.buf[x as usize][y as usize] = Some(color);
             }
         }
     }
     fn visible_tiles(&mut self) -> Vec<tile::Tile> {
-        let center = util::coords_to_tile(&self.center, self.zoom as f64);
-
-        let tile_screen_size = 256.0 * self.rel_zoom;
-        let lon = self.center.lon;
-        let lat = self.center.lat;
+        load_visible_tiles(
+            self.tilesource.as_mut(),
+            self.projection.as_ref(),
+            &self.center,
+            self.zoom,
+            (self.width, self.height),
+            256.0 * self.rel_zoom,
+        )
+    }
+    fn poll_tiles(&mut self) -> bool {
+        self.tilesource.poll()
+    }
+    fn tile_stats(&self) -> TileStats {
+        self.tilesource.stats()
+    }
+    fn features_at(&mut self, p: (f64, f64), radius: f64) -> Vec<Hit> {
+        let tiles = self.visible_tiles();
+        find_features(&tiles, &self.style, &*self, p, radius)
+    }
+}
 
-        // center tile
-        let mut ct = tile::Tile {
-            zxy: (self.zoom as usize, center.x as i32, center.y as i32),
-            ..Default::default()
-        };
+impl ScreenSpace for BrailleRenderer {
+    fn view(&self) -> View<'_> {
+        View {
+            projection: self.projection.as_ref(),
+            center: &self.center,
+            zoom: self.zoom,
+            rel_zoom: self.rel_zoom,
+            size: (self.width, self.height),
+        }
+    }
+}
 
-        let dx = (lon - ct.bounds().w) / (ct.bounds().e - ct.bounds().w);
-        let dy = ((1. - ((lat * PI / 180.).tan() + 1. / (lat * PI / 180.).cos()).ln() / PI) / 2.
-            * 2f64.powf(self.zoom as f64))
-        .fract();
-        ct.screenpos = (
-            (self.width as f64 / 2. - tile_screen_size * dx).round() as i32,
-            (self.height as f64 / 2. - tile_screen_size * dy).round() as i32,
-        );
+impl BrailleRenderer {
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
 
-        let hcnt = 1 + (self.width as f64 / tile_screen_size).ceil() as i32;
-        let vcnt = 1 + (self.height as f64 / tile_screen_size).ceil() as i32;
-        info!("dx = {:.2}, dy = {:.2}", dx, dy);
-        info!("hcnt = {}, vcnt = {}", hcnt, vcnt);
-
-        let modulo = 2i32.pow(self.zoom);
-
-        let mut tiles: Vec<tile::Tile> = Vec::new();
-        for i in -vcnt..vcnt + 1 {
-            for j in -hcnt..hcnt + 1 {
-                let mut x = (j + center.x as i32) % modulo;
-                let mut y = (i + center.y as i32) % modulo;
-
-                let mut t = tile::Tile {
-                    zxy: (self.zoom as usize, x, y),
-                    screenpos: (
-                        (self.width as f64 / 2. - tile_screen_size * dx
-                            + j as f64 * tile_screen_size)
-                            .round() as i32,
-                        (self.height as f64 / 2. - tile_screen_size * dy
-                            + i as f64 * tile_screen_size)
-                            .round() as i32,
-                    ),
-                    ..Default::default()
-                };
-
-                let top_l = t.screenpos;
-                let bot_r = (
-                    top_l.0 + tile_screen_size.round() as i32,
-                    top_l.1 + tile_screen_size.round() as i32,
-                );
-
-                if util::rectangles_intersect(
-                    (top_l, bot_r),
-                    ((0, 0), (self.width as i32, self.height as i32)),
-                ) {
-                    tiles.push(t);
-                }
-            }
+    // Changes the size of the canvas, in dots. Takes effect on the next draw.
+    pub fn resize(&mut self, size: (usize, usize)) {
+        if size != self.size() {
+            (self.width, self.height) = size;
+            self.buf = vec![vec![None; size.1]; size.0];
         }
+    }
 
-        tiles.iter_mut().for_each(|ref mut t| {
-            match self.tilesource.get_tile(t.z(), t.x(), t.y()) {
-                Ok(tile) => {
-                    t.vtile = tile.vtile;
-                }
-                Err(e) => {
-                    error!("Could not get vector tile: {}", e);
-                }
-            }
-        });
+    // Moves the map so that the place under `from` ends up under `to`.
+    pub fn drag(&mut self, from: (f64, f64), to: (f64, f64)) {
+        let middle = (self.width as f64 / 2., self.height as f64 / 2.);
+        if let Some(c) = self.screen_to_coords((middle.0 + from.0 - to.0, middle.1 + from.1 - to.1))
+        {
+            let max_lat = self.projection.max_lat();
+            self.center = Coords::from_deg(c.lat.clamp(-max_lat, max_lat), c.lon);
+        }
+    }
 
-        tiles
+    // Zooms in or out keeping the place under `p` where it is.
+    pub fn zoom_at(&mut self, p: (f64, f64), zoom_in: bool) {
+        let place = self.screen_to_coords(p);
+        if zoom_in {
+            self.zoom_in();
+        } else {
+            self.zoom_out();
+        }
+        if let Some(c) = place {
+            self.drag(self.coords_to_screen(&c), p);
+        }
     }
-}
 
-impl BrailleRenderer {
     pub fn point_within_bounds(&self, p: (i32, i32)) -> bool {
         let (x, y) = p;
         0 <= x && x < self.width as i32 && 0 <= y && y < self.height as i32
@@ -362,108 +636,225 @@ impl BrailleRenderer {
             && t.screenpos.1 < y
             && y < t.screenpos.1 + tile_screen_size
     }
-    // Each vec of points represents a polyline. There are potentially multiple polylines.
-    pub fn commands_to_polylines(&self, commands: &Vec<GeometryCommand>) -> Vec<Vec<(i32, i32)>> {
-        let mut lines = Vec::new();
-        let mut line = Vec::new();
-
-        let mut cursor = (0, 0);
-        for c in commands {
-            match c {
-                tile::GeometryCommand::MoveTo(dx, dy) => {
-                    cursor = (cursor.0 + dx, cursor.1 + dy);
 
-                    if !line.is_empty() {
-                        lines.push(line);
-                    }
-                    line = Vec::new();
-                }
-                tile::GeometryCommand::LineTo(dx, dy) => {
-                    if line.is_empty() || line.last().unwrap() != &cursor {
-                        line.push(cursor);
+    // Debug overlay: the tile's top and left edges, so that neighbors draw the
+    // rest of the grid, and its z/x/y in the top-left corner. Labels placed by
+    // then are written over, since this is drawn last.
+    fn draw_tile_outline(&mut self, t: &Tile) {
+        let tile_screen_size = (256. * self.rel_zoom).round() as i32;
+        let (z, x, y) = t.zxy;
+        let mut edges = Vec::new();
+        if self.projection.is_web_mercator() {
+            let (x0, y0) = t.screenpos;
+            for i in 0..tile_screen_size {
+                edges.extend([(x0 + i, y0), (x0, y0 + i)]);
+            }
+        } else {
+            // Projected edges bend, so they're traced point by point.
+            let n = 2f64.powf(z as f64);
+            let at = |fx: f64, fy: f64| {
+                Coords::from_deg(
+                    (PI * (1. - 2. * fy / n)).sinh().atan().to_degrees(),
+                    fx / n * 360. - 180.,
+                )
+            };
+            for i in 0..tile_screen_size {
+                let f = i as f64 / tile_screen_size as f64;
+                for c in [at(x as f64 + f, y as f64), at(x as f64, y as f64 + f)] {
+                    if self.projection.is_visible(&c, &self.center) {
+                        let (px, py) = self.tile_coords_to_screen(t, &c);
+                        edges.push((px.round() as i32, py.round() as i32));
                     }
-                    cursor = (cursor.0 + dx, cursor.1 + dy);
-                    line.push(cursor);
-                }
-                tile::GeometryCommand::ClosePath => {
-                    // line.push(line[0]);
                 }
             }
         }
-        if !line.is_empty() {
-            lines.push(line);
+        for p in edges {
+            if self.point_within_bounds(p) {
+                self.buf[p.0 as usize][p.1 as usize] = Some(DEBUG_COLOR);
+            }
         }
 
-        lines
-    }
-    pub fn tile_point_to_screen_space(
-        &mut self,
-        t: &Tile,
-        p: (i32, i32),
-        extent: u32,
-    ) -> sp::Point<f32> {
-        let base_size = 256.;
-        sp::Point {
-            x: t.screenpos.0 as f32 + p.0 as f32 * base_size / extent as f32 * self.rel_zoom as f32,
-            y: t.screenpos.1 as f32 + p.1 as f32 * base_size / extent as f32 * self.rel_zoom as f32,
+        let (x0, y0) = t.screenpos;
+        let text = format!("{}/{}/{}", z, x, y);
+        let (cols, rows) = ((self.width / 2) as i32, (self.height / 4) as i32);
+        let (col, row) = ((x0.max(0) + 1) / 2, (y0.max(0) + 3) / 4);
+        let len = text.chars().count() as i32;
+        if col + len <= cols && row < rows {
+            self.labels.push(Label {
+                text,
+                col,
+                row,
+                color: DEBUG_COLOR,
+                priority: (0, 0),
+            });
         }
     }
 
     pub fn to_braille(&mut self) -> Vec<String> {
+        self.to_braille_cells()
+            .into_iter()
+            .map(|row| row.into_iter().map(|(c, _)| c).collect())
+            .collect()
+    }
+
+    // Each braille character takes the color that most of its dots were drawn with.
+    pub fn to_braille_cells(&self) -> Vec<Vec<(char, Option<Rgb<u8>>)>> {
         let mut lines = Vec::new();
-        for y in 0..(self.height/4) {
-            let mut s = String::with_capacity(self.width/2);
-            for x in 0..(self.width/2) {
-                s.push(
-                    BRAILLE
-                    [self.buf[2*x][4 * y] as usize][self.buf[2 * x+1][4 * y] as usize]
-                    [self.buf[2*x][4 * y+1] as usize][self.buf[2 * x+1][4 * y + 1] as usize]
-                    [self.buf[2*x][4 * y+2] as usize][self.buf[2 * x+1][4 * y + 2] as usize]
-                    [self.buf[2*x][4 * y+3] as usize][self.buf[2 * x+1][4 * y + 3] as usize]
-                );
-            }
-            lines.push(s);
+        for y in 0..(self.height / 4) {
+            let mut line = Vec::with_capacity(self.width / 2);
+            for x in 0..(self.width / 2) {
+                let dot = |dx: usize, dy: usize| self.buf[2 * x + dx][4 * y + dy];
+                let c = BRAILLE[dot(0, 0).is_some() as usize][dot(1, 0).is_some() as usize]
+                    [dot(0, 1).is_some() as usize][dot(1, 1).is_some() as usize]
+                    [dot(0, 2).is_some() as usize][dot(1, 2).is_some() as usize]
+                    [dot(0, 3).is_some() as usize][dot(1, 3).is_some() as usize];
+                let color =
+                    majority_color((0..4).flat_map(|dy| (0..2).filter_map(move |dx| dot(dx, dy))));
+                line.push((c, color));
+            }
+            lines.push(line);
+        }
+
+        for label in &self.labels {
+            let line = &mut lines[label.row as usize];
+            for (i, c) in label.text.chars().enumerate() {
+                line[label.col as usize + i] = (c, Some(label.color));
+            }
         }
         lines
     }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn to_ansi(&self, mode: ColorMode) -> Vec<String> {
+        self.to_braille_cells()
+            .into_iter()
+            .map(|row| {
+                let mut s = String::new();
+                let mut current = None;
+                for (c, color) in row {
+                    if color != current {
+                        match color {
+                            Some(Rgb([r, g, b])) => match mode {
+                                ColorMode::Ansi256 => s.push_str(&format!(
+                                    "\x1b[38;5;{}m",
+                                    util::rgb_to_ansi256(Rgb([r, g, b]))
+                                )),
+                                ColorMode::TrueColor => {
+                                    s.push_str(&format!("\x1b[38;2;{};{};{}m", r, g, b))
+                                }
+                            },
+                            None => s.push_str("\x1b[0m"),
+                        }
+                        current = color;
+                    }
+                    s.push(c);
+                }
+                if current.is_some() {
+                    s.push_str("\x1b[0m");
+                }
+                s
+            })
+            .collect()
+    }
+}
+
+impl Label {
+    fn len(&self) -> i32 {
+        self.text.chars().count() as i32
+    }
+
+    // Labels on the same row need at least one blank cell between them.
+    fn overlaps(&self, other: &Label) -> bool {
+        self.row == other.row
+            && self.col <= other.col + other.len()
+            && other.col <= self.col + self.len()
+    }
+}
+
+// Greedily keeps the most important labels that fit on a cols x rows grid
+// without overlapping each other.
+fn place_labels(mut candidates: Vec<Label>, cols: i32, rows: i32) -> Vec<Label> {
+    candidates.sort_by_key(|l| l.priority);
+
+    let mut placed: Vec<Label> = Vec::new();
+    for mut l in candidates {
+        if l.len() > cols || l.row < 0 || l.row >= rows {
+            continue;
+        }
+        l.col = l.col.clamp(0, cols - l.len());
+
+        let duplicate = placed.iter().any(|p| {
+            p.text == l.text
+                && (p.row - l.row).abs() <= LABEL_DEDUP_DISTANCE
+                && (p.col - l.col).abs() <= LABEL_DEDUP_DISTANCE
+        });
+        if !duplicate && !placed.iter().any(|p| p.overlaps(&l)) {
+            placed.push(l);
+        }
+    }
+    placed
+}
+
+fn majority_color(colors: impl Iterator<Item = Rgb<u8>>) -> Option<Rgb<u8>> {
+    let mut counts: Vec<(Rgb<u8>, usize)> = Vec::new();
+    for c in colors {
+        match counts.iter_mut().find(|(k, _)| *k == c) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((c, 1)),
+        }
+    }
+    // Ties go to whichever color was seen first, scanning the cell row by row.
+    counts
+        .into_iter()
+        .fold(None, |best: Option<(Rgb<u8>, usize)>, (c, n)| match best {
+            Some((_, m)) if m >= n => best,
+            _ => Some((c, n)),
+        })
+        .map(|(c, _)| c)
 }
 
 impl Renderer for ImageRenderer {
-    fn new(res: (usize, usize), center: Coords) -> Self {
+    fn new(res: (usize, usize), center: Coords, tilesource: Box<dyn TileSource>) -> Self {
         ImageRenderer {
             width: res.0,
             height: res.1,
-            center,
+            center: Coords::from_deg(center.lat, util::normalize_lon(center.lon)),
             zoom: 0,
-            tilesource: Box::new(CachedTileSource::unbounded(TileServerSource::new())),
+            projection: Box::new(WebMercator),
+            tilesource,
             img: ImageBuffer::new(res.0 as u32, res.1 as u32),
-            rel_zoom: 10.,
+            rel_zoom: 1.,
             simplify: false,
             tolerance: 1.,
             high_quality: false,
+            style: Style::default(),
+            stats: RenderStats::default(),
         }
     }
     fn zoom_in(&mut self) {
-        self.zoom = std::cmp::min(self.zoom + 1, MAX_ZOOM);
+        self.zoom = std::cmp::min(self.zoom + 1, self.tilesource.zoom_range().1 + MAX_OVERZOOM);
     }
     fn zoom_out(&mut self) {
-        if self.zoom > std::cmp::max(0, MIN_ZOOM) {
+        if self.zoom > self.tilesource.zoom_range().0 {
             self.zoom -= 1;
         }
     }
     fn pan(&mut self, d: Direction) {
         match d {
             Direction::RIGHT => {
-                self.center.lon += 10.;
+                self.center.lon = util::normalize_lon(self.center.lon + 10.);
             }
             Direction::LEFT => {
-                self.center.lon -= 10.;
+                self.center.lon = util::normalize_lon(self.center.lon - 10.);
             }
             Direction::UP => {
-                self.center.lat = (self.center.lat + 10.).min(80.);
+                self.center.lat = (self.center.lat + 10.).min(self.projection.max_lat());
             }
             Direction::DOWN => {
-                self.center.lat = (self.center.lat - 10.).max(-80.);
+                self.center.lat = (self.center.lat - 10.).max(-self.projection.max_lat());
             }
         };
     }
@@ -476,122 +867,139 @@ impl Renderer for ImageRenderer {
 
     fn draw(&mut self) {
         self.clear_buf();
-
-        let mut tiles: Vec<tile::Tile> = self.visible_tiles();
-        info!("There are {} visible tiles", tiles.len());
-
-        // for (x, y, pixel) in self.img.enumerate_pixels_mut() {
-        //     let r = (100.0 + 0.2 * x as f32) as u8;
-        //     let b = (100.0 + 0.2 * y as f32) as u8;
-        //     *pixel = image::Rgb([r, 0, b]);
-        // }
-        for t in &tiles {
-            self.draw_tile(&t);
-            // info!("Screen position of tile: {:?}", self.screen_position(&t));
-        }
-        self.img.save("test.png").unwrap();
+        self.stats = RenderStats::default();
+
+        let ((), draw_time) = stats::timed(|| {
+            let tiles: Vec<tile::Tile> = self.visible_tiles();
+            info!("There are {} visible tiles", tiles.len());
+
+            // for (x, y, pixel) in self.img.enumerate_pixels_mut() {
+            //     let r = (100.0 + 0.2 * x as f32) as u8;
+            //     let b = (100.0 + 0.2 * y as f32) as u8;
+            //     *pixel = image::Rgb([r, 0, b]);
+            // }
+            if !self.projection.is_web_mercator() {
+                self.draw_background();
+            }
+            for t in &tiles {
+                self.draw_tile(t);
+                // info!("Screen position of tile: {:?}", self.screen_position(&t));
+            }
+        });
+        self.stats.draw_time = draw_time;
     }
     fn draw_tile(&mut self, t: &tile::Tile) {
         info!("bounding box: {:?}", t.bounds());
-        let layer_colors = hashmap! {
-            "aeroway" => Rgb([47, 79, 79]),
-            "boundary" => Rgb([107, 142, 35]),
-            "building" => Rgb([100, 149, 237]),
-            "housenumber" => Rgb([192, 192, 192]),
-            "landcover" => Rgb([221, 160, 221]),
-            "landuse" => Rgb([106, 90, 205]),
-            "mountain_peak" => Rgb([255, 248, 220]),
-            "park" => Rgb([255, 228, 196]),
-            "place" => Rgb([253, 150, 147]),
-            "poi" => Rgb([4, 88, 187]),
-            "transportation" => Rgb([241, 5, 37]),
-            "transportation_name" => Rgb([19, 79, 94]),
-            "water" => Rgb([176, 1, 20]),
-            "water_name" => Rgb([254, 70, 216]),
-            "waterway" => Rgb([107, 41, 12])
-        };
-
+        self.stats.tiles += 1;
+        if t.overzoom > 0 {
+            self.stats.overzoomed += 1;
+        }
         let tile_screen_size = (256. * self.rel_zoom).round() as i32;
         for i in 0..tile_screen_size {
             for j in 0..tile_screen_size {
                 let x = t.screenpos.0 + i;
                 let y = t.screenpos.1 + j;
-                if self.point_within_bounds((x, y)) {
+                if self.projection.is_web_mercator() && self.point_within_bounds((x, y)) {
                     self.img.put_pixel(x as u32, y as u32, Rgb([0, 0, 0]));
                 }
             }
         }
 
         if let Some(vtile) = t.vtile.as_ref() {
-            for layer in &vtile.layers {
-                let extent = layer.extent();
-
-                let color = layer_colors
-                    .get(layer.name.as_str())
-                    .unwrap_or(&Rgb([255, 255, 255]));
-
-                info!("layer,{}", layer.name);
-
-                for feature in &layer.features {
-                    let commands = tile::Tile::parse_geometry(&feature.geometry);
+            let rules: Vec<LayerStyle> = self.style.layers_at(t.z() as u32).cloned().collect();
+            for rule in &rules {
+                let color = rule.rgb();
+
+                for layer in vtile.layers.iter().filter(|l| l.name == rule.layer) {
+                    let extent = layer.extent();
+                    if extent == 0 {
+                        warn!("Skipping layer {} with zero extent", layer.name);
+                        continue;
+                    }
 
-                    match feature.r#type() {
-                        GeomType::Unknown => {
-                            panic!("Found unknown geometry, don't know how to interpret this");
-                        }
-                        GeomType::Point => {
-                            let mut cursor = (0, 0);
-                            for c in commands {
-                                match c {
-                                    tile::GeometryCommand::MoveTo(dx, dy) => {
-                                        let nc = (cursor.0 + dx, cursor.1 + dy);
-
-                                        let p = self.tile_point_to_screen_space(t, nc, extent);
-                                        let p = (p.x.round() as i32, p.y.round() as i32);
+                    info!("layer,{}", layer.name);
+
+                    for feature in layer
+                        .features
+                        .iter()
+                        .map(|f| Feature::new(layer, f))
+                        .filter(|f| rule.matches(f))
+                    {
+                        let geometry = match feature.geometry() {
+                            Ok(geometry) => geometry,
+                            Err(e) => {
+                                self.stats.dropped += 1;
+                                warn!(
+                                    "Skipping feature {:?} of layer {}: {}",
+                                    feature.id(),
+                                    layer.name,
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+                        *self.stats.features.entry(layer.name.clone()).or_default() += 1;
+                        let geometry =
+                            self.cut_for_view(t, geometry, extent, rule.kind == DrawKind::Fill);
+
+                        match geometry {
+                            Geometry::MultiPoint(points) => {
+                                for p in points {
+                                    let p = self.tile_point_to_screen_space(t, p, extent);
+                                    let p = (p.x.round() as i32, p.y.round() as i32);
 
-                                        if self.point_within_bounds(p) {
-                                            self.img.put_pixel(
-                                                p.0 as u32,
-                                                p.1 as u32,
-                                                Rgb([255, 255, 255]),
-                                            );
-                                        }
-                                        cursor = nc;
-                                    }
-                                    _ => {
-                                        panic!("Point geometry can only contain MoveTo commands");
+                                    if self.point_within_bounds(p)
+                                        && (t.overzoom == 0
+                                            || !self.projection.is_web_mercator()
+                                            || self.point_within_tile_bounds(t, p))
+                                    {
+                                        self.img.put_pixel(p.0 as u32, p.1 as u32, color);
                                     }
-                                };
+                                }
                             }
-                        }
-                        GeomType::Linestring | GeomType::Polygon => {
-                            let mut lines: Vec<Vec<sp::Point<f32>>> = self
-                                .commands_to_polylines(&commands)
-                                .into_iter()
-                                .map(|line| {
-                                    line.into_iter()
-                                        .map(|p| self.tile_point_to_screen_space(t, p, extent))
-                                        .collect()
-                                })
-                                .collect();
-
-                            if self.simplify {
-                                for i in 0..lines.len() {
-                                    let before = lines[i].len();
-                                    lines[i] = sp::simplify(&lines[i], self.tolerance, false);
-                                    let after = lines[i].len();
-
-                                    info!("Simplified from {} points to {} points", before, after);
+                            Geometry::MultiPolygon(polygons) if rule.kind == DrawKind::Fill => {
+                                for rings in polygons {
+                                    let rings: Vec<Vec<sp::Point<f32>>> = rings
+                                        .iter()
+                                        .map(|ring| self.ring_to_screen_space(t, ring, extent))
+                                        .collect();
+                                    self.fill_polygon(t, &rings, color);
                                 }
                             }
+                            geometry => {
+                                let mut lines: Vec<Vec<sp::Point<f32>>> = geometry
+                                    .into_lines()
+                                    .into_iter()
+                                    .map(|line| {
+                                        line.into_iter()
+                                            .map(|p| self.tile_point_to_screen_space(t, p, extent))
+                                            .collect()
+                                    })
+                                    .collect();
+
+                                if self.simplify {
+                                    for line in lines.iter_mut() {
+                                        let before = line.len();
+                                        *line = sp::simplify(line, self.tolerance, false);
+                                        let after = line.len();
+                                        self.stats.points_before_simplify += before;
+                                        self.stats.points_after_simplify += after;
+
+                                        info!(
+                                            "Simplified from {} points to {} points",
+                                            before, after
+                                        );
+                                    }
+                                }
 
-                            lines.iter().for_each(|line| {
-                                line.iter().zip(line.iter().skip(1)).for_each(|(p, q)| {
-                                    let p = (p.x.round() as i32, p.y.round() as i32);
-                                    let q = (q.x.round() as i32, q.y.round() as i32);
-                                    self.draw_line(t, p, q, extent, *color);
+                                lines.iter().for_each(|line| {
+                                    line.iter().zip(line.iter().skip(1)).for_each(|(p, q)| {
+                                        let p = (p.x.round() as i32, p.y.round() as i32);
+                                        let q = (q.x.round() as i32, q.y.round() as i32);
+                                        self.draw_line(t, p, q, extent, color);
+                                    });
                                 });
-                            });
+                            }
                         }
                     }
                 }
@@ -613,6 +1021,17 @@ impl Renderer for ImageRenderer {
         // }
     }
     fn draw_line(&mut self, t: &Tile, p: (i32, i32), q: (i32, i32), extent: u32, color: Rgb<u8>) {
+        if t.overzoom > 0 || !self.projection.is_web_mercator() {
+            // Scaled-up lines reach far past the tile, so they're cut to it.
+            // Reprojected lines can end up anywhere, so they're cut to the
+            // screen.
+            if let Some((p, q)) = geometry::clip_segment(p, q, self.tile_clip_rect(t)) {
+                let fp = (p.0 as f32, p.1 as f32);
+                let fq = (q.0 as f32, q.1 as f32);
+                imageproc::drawing::draw_line_segment_mut(&mut self.img, fp, fq, color);
+            }
+            return;
+        }
         let fp = (p.0 as f32, p.1 as f32);
         let fq = (q.0 as f32, q.1 as f32);
         if self.point_within_bounds(p)
@@ -622,128 +1041,78 @@ impl Renderer for ImageRenderer {
             imageproc::drawing::draw_line_segment_mut(&mut self.img, fp, fq, color);
         }
     }
-    fn visible_tiles(&mut self) -> Vec<tile::Tile> {
-        let center = util::coords_to_tile(&self.center, self.zoom as f64);
-
-        let tile_screen_size = 256.0 * self.rel_zoom;
-        let lon = self.center.lon;
-        let lat = self.center.lat;
-
-        // center tile
-        let mut ct = tile::Tile {
-            zxy: (self.zoom as usize, center.x as i32, center.y as i32),
-            ..Default::default()
-        };
-
-        let dx = (lon - ct.bounds().w) / (ct.bounds().e - ct.bounds().w);
-        let dy = ((1. - ((lat * PI / 180.).tan() + 1. / (lat * PI / 180.).cos()).ln() / PI) / 2.
-            * 2f64.powf(self.zoom as f64))
-        .fract();
-        ct.screenpos = (
-            (self.width as f64 / 2. - tile_screen_size * dx).round() as i32,
-            (self.height as f64 / 2. - tile_screen_size * dy).round() as i32,
-        );
-
-        let hcnt = 1 + (self.width as f64 / tile_screen_size).ceil() as i32;
-        let vcnt = 1 + (self.height as f64 / tile_screen_size).ceil() as i32;
-        info!("dx = {:.2}, dy = {:.2}", dx, dy);
-        info!("hcnt = {}, vcnt = {}", hcnt, vcnt);
-
-        let modulo = 2i32.pow(self.zoom);
-
-        let mut tiles: Vec<tile::Tile> = Vec::new();
-        for i in -vcnt..vcnt + 1 {
-            for j in -hcnt..hcnt + 1 {
-                let mut x = (j + center.x as i32) % modulo;
-                let mut y = (i + center.y as i32) % modulo;
-
-                let mut t = tile::Tile {
-                    zxy: (self.zoom as usize, x, y),
-                    screenpos: (
-                        (self.width as f64 / 2. - tile_screen_size * dx
-                            + j as f64 * tile_screen_size)
-                            .round() as i32,
-                        (self.height as f64 / 2. - tile_screen_size * dy
-                            + i as f64 * tile_screen_size)
-                            .round() as i32,
-                    ),
-                    ..Default::default()
-                };
-
-                let top_l = t.screenpos;
-                let bot_r = (
-                    top_l.0 + tile_screen_size.round() as i32,
-                    top_l.1 + tile_screen_size.round() as i32,
-                );
-
-                if ImageRenderer::rectangles_intersect(
-                    (top_l, bot_r),
-                    ((0, 0), (self.width as i32, self.height as i32)),
-                ) {
-                    tiles.push(t);
-                }
+    fn fill_polygon(&mut self, t: &Tile, rings: &[Vec<sp::Point<f32>>], color: Rgb<u8>) {
+        for (y, x0, x1) in geometry::scanline_fill(rings, self.tile_clip_rect(t)) {
+            for x in x0..=x1 {
+                self.img.put_pixel(x as u32, y as u32, color);
             }
         }
+    }
+    fn visible_tiles(&mut self) -> Vec<tile::Tile> {
+        load_visible_tiles(
+            self.tilesource.as_mut(),
+            self.projection.as_ref(),
+            &self.center,
+            self.zoom,
+            (self.width, self.height),
+            256.0 * self.rel_zoom,
+        )
+    }
+    fn poll_tiles(&mut self) -> bool {
+        self.tilesource.poll()
+    }
+    fn tile_stats(&self) -> TileStats {
+        self.tilesource.stats()
+    }
+    fn features_at(&mut self, p: (f64, f64), radius: f64) -> Vec<Hit> {
+        let tiles = self.visible_tiles();
+        find_features(&tiles, &self.style, &*self, p, radius)
+    }
+}
 
-        tiles.iter_mut().for_each(|ref mut t| {
-            match self.tilesource.get_tile(t.z(), t.x(), t.y()) {
-                Ok(tile) => {
-                    t.vtile = tile.vtile;
-                }
-                Err(e) => {
-                    error!("Could not get vector tile: {}", e);
-                }
-            }
-        });
-
-        tiles
+impl ScreenSpace for ImageRenderer {
+    fn view(&self) -> View<'_> {
+        View {
+            projection: self.projection.as_ref(),
+            center: &self.center,
+            zoom: self.zoom,
+            rel_zoom: self.rel_zoom,
+            size: (self.width, self.height),
+        }
     }
 }
 
 impl ImageRenderer {
-    // Each vec of points represents a polyline. There are potentially multiple polylines.
-    pub fn commands_to_polylines(&self, commands: &Vec<GeometryCommand>) -> Vec<Vec<(i32, i32)>> {
-        let mut lines = Vec::new();
-        let mut line = Vec::new();
-
-        let mut cursor = (0, 0);
-        for c in commands {
-            match c {
-                tile::GeometryCommand::MoveTo(dx, dy) => {
-                    cursor = (cursor.0 + dx, cursor.1 + dy);
+    // Writes the last drawn frame, in the format given by the file extension.
+    pub fn save(&self, path: impl AsRef<Path>) -> image::ImageResult<()> {
+        self.img.save(path)
+    }
 
-                    if !line.is_empty() {
-                        lines.push(line);
-                    }
-                    line = Vec::new();
-                }
-                tile::GeometryCommand::LineTo(dx, dy) => {
-                    if line.is_empty() || line.last().unwrap() != &cursor {
-                        line.push(cursor);
-                    }
-                    cursor = (cursor.0 + dx, cursor.1 + dy);
-                    line.push(cursor);
-                }
-                tile::GeometryCommand::ClosePath => {
-                    // line.push(line[0]);
-                }
+    // Reprojected tiles don't cover the screen in squares, so the map's
+    // background is painted wherever the projection shows some place.
+    fn draw_background(&mut self) {
+        let scale = 256. * self.rel_zoom * 2f64.powf(self.zoom as f64);
+        let size = (self.width, self.height);
+        for (x, y, pixel) in self.img.enumerate_pixels_mut() {
+            let p = (x as f64 + 0.5, y as f64 + 0.5);
+            if self
+                .projection
+                .screen_to_coords(p, &self.center, scale, size)
+                .is_some()
+            {
+                *pixel = Rgb([0, 0, 0]);
             }
         }
-        if !line.is_empty() {
-            lines.push(line);
-        }
-
-        lines
     }
 
     pub fn get_tile_features(&self, tile: &tile::Tile, zoom: f64) {
-        let draw_order = ImageRenderer::generate_draw_order(zoom);
-        println!("draw order is {:?}", draw_order);
+        let draw_order = self.style.draw_order(zoom as u32);
+        debug!("draw order is {:?}", draw_order);
 
         let vtile = tile.vtile.as_ref().unwrap();
 
         vtile.layers.iter().for_each(|l| {
-            println!(
+            debug!(
                 "layer: {} (version={}, {} features, {} keys, {} values)",
                 l.name,
                 l.version,
@@ -751,23 +1120,13 @@ impl ImageRenderer {
                 l.keys.len(),
                 l.values.len()
             );
-            // println!("\tkeys: {:?}", l.keys);
+            debug!("\tkeys: {:?}", l.keys);
+            for f in l.features.iter().map(|f| Feature::new(l, f)) {
+                debug!("\t{:?} {:?}", f.geom_type(), f.properties);
+            }
         });
     }
 
-    pub fn tile_point_to_screen_space(
-        &mut self,
-        t: &Tile,
-        p: (i32, i32),
-        extent: u32,
-    ) -> sp::Point<f32> {
-        let base_size = 256.;
-        sp::Point {
-            x: t.screenpos.0 as f32 + p.0 as f32 * base_size / extent as f32 * self.rel_zoom as f32,
-            y: t.screenpos.1 as f32 + p.1 as f32 * base_size / extent as f32 * self.rel_zoom as f32,
-        }
-    }
-
     pub fn point_within_bounds(&self, p: (i32, i32)) -> bool {
         let (x, y) = p;
         0 <= x && x < self.width as i32 && 0 <= y && y < self.height as i32
@@ -781,39 +1140,702 @@ impl ImageRenderer {
             && t.screenpos.1 < y
             && y < t.screenpos.1 + tile_screen_size
     }
+}
 
-    pub fn generate_draw_order(zoom: f64) -> Vec<String> {
-        let features = if zoom < 2. {
-            vec!["admin", "water", "country_label", "marine_label"]
-        } else {
-            vec![
-                "landuse",
-                "water",
-                "marine_label",
-                "building",
-                "road",
-                "admin",
-                "country_label",
-                "state_label",
-                "water_label",
-                "place_label",
-                "rail_station_label",
-                "poi_label",
-                "road_label",
-                "housenum_label",
-            ]
+// The tiles covering a screen of `size` pixels centered on `center`, with
+// their geometry loaded. Tiles just off screen are requested as well.
+pub(crate) fn load_visible_tiles(
+    ts: &mut dyn TileSource,
+    projection: &dyn Projection,
+    center: &Coords,
+    zoom: u32,
+    size: (usize, usize),
+    tile_screen_size: f64,
+) -> Vec<tile::Tile> {
+    let (mut tiles, around) = if projection.is_web_mercator() {
+        grid_tiles(center, zoom, size, tile_screen_size)
+    } else {
+        projected_tiles(projection, center, zoom, size, tile_screen_size)
+    };
+
+    request_tiles(
+        ts,
+        &tiles,
+        &around,
+        (size.0 as i32, size.1 as i32),
+        tile_screen_size.round() as i32,
+    );
+    for t in tiles.iter_mut() {
+        load_tile(ts, t);
+    }
+
+    tiles
+}
+
+// In Web Mercator tiles are laid out on screen as a grid, so the visible ones
+// and the ones just around them follow from the center tile.
+fn grid_tiles(
+    center: &Coords,
+    zoom: u32,
+    size: (usize, usize),
+    tile_screen_size: f64,
+) -> (Vec<Tile>, Vec<Tile>) {
+    let (width, height) = size;
+    let lon = util::normalize_lon(center.lon);
+    let ctile = util::coords_to_tile(&Coords::from_deg(center.lat, lon), zoom as f64);
+    let lat = center.lat;
+
+    // center tile
+    let ct = tile::Tile {
+        zxy: (zoom as usize, ctile.x as i32, ctile.y as i32),
+        ..Default::default()
+    };
+
+    let dx = (lon - ct.bounds().w) / (ct.bounds().e - ct.bounds().w);
+    let dy = ((1. - ((lat * PI / 180.).tan() + 1. / (lat * PI / 180.).cos()).ln() / PI) / 2.
+        * 2f64.powf(zoom as f64))
+    .fract();
+
+    let hcnt = 1 + (width as f64 / tile_screen_size).ceil() as i32;
+    let vcnt = 1 + (height as f64 / tile_screen_size).ceil() as i32;
+    info!("dx = {:.2}, dy = {:.2}", dx, dy);
+    info!("hcnt = {}, vcnt = {}", hcnt, vcnt);
+
+    let n = 2i32.pow(zoom);
+
+    let mut tiles: Vec<tile::Tile> = Vec::new();
+    // Tiles just off screen, loaded ahead of panning.
+    let mut around: Vec<tile::Tile> = Vec::new();
+    for i in -vcnt..vcnt + 1 {
+        for j in -hcnt..hcnt + 1 {
+            // The world repeats east and west, but not north and south.
+            let x = (j + ctile.x as i32).rem_euclid(n);
+            let y = i + ctile.y as i32;
+            if !(0..n).contains(&y) {
+                continue;
+            }
+
+            let t = tile::Tile {
+                zxy: (zoom as usize, x, y),
+                screenpos: (
+                    (width as f64 / 2. - tile_screen_size * dx + j as f64 * tile_screen_size)
+                        .round() as i32,
+                    (height as f64 / 2. - tile_screen_size * dy + i as f64 * tile_screen_size)
+                        .round() as i32,
+                ),
+                ..Default::default()
+            };
+
+            let top_l = t.screenpos;
+            let bot_r = (
+                top_l.0 + tile_screen_size.round() as i32,
+                top_l.1 + tile_screen_size.round() as i32,
+            );
+
+            let size = tile_screen_size.round() as i32;
+            let (w, h) = (width as i32, height as i32);
+            if util::rectangles_intersect((top_l, bot_r), ((0, 0), (w, h))) {
+                tiles.push(t);
+            } else if util::rectangles_intersect(
+                (top_l, bot_r),
+                ((-size, -size), (w + size, h + size)),
+            ) {
+                around.push(t);
+            }
+        }
+    }
+    (tiles, around)
+}
+
+// Other projections bend and cut up the tile grid, so the visible tiles are
+// the ones under a lattice of screen points, found through lon/lat. Their
+// screen position is where their top left corner ends up.
+fn projected_tiles(
+    projection: &dyn Projection,
+    center: &Coords,
+    zoom: u32,
+    size: (usize, usize),
+    tile_screen_size: f64,
+) -> (Vec<Tile>, Vec<Tile>) {
+    let (width, height) = (size.0 as f64, size.1 as f64);
+    let scale = tile_screen_size * 2f64.powf(zoom as f64);
+    let n = 1i32 << zoom;
+    let step = (tile_screen_size / 4.).clamp(1., 8.);
+
+    // Tiles are keyed by x counted from the west edge of the center's copy of
+    // the world, so that copies of the same tile are told apart.
+    let repeats = projection.world_width().is_some();
+    let mut visible: BTreeSet<(i32, i32)> = BTreeSet::new();
+    for i in 0..=(width / step).ceil() as i32 {
+        for j in 0..=(height / step).ceil() as i32 {
+            let p = ((i as f64 * step).min(width), (j as f64 * step).min(height));
+            let Some((c, world)) = projection.screen_to_coords(p, center, scale, size) else {
+                continue;
+            };
+            if c.lat.abs() > projection::MAX_TILE_LAT {
+                continue;
+            }
+            let x = ((c.lon + 180.) / 360. * n as f64).floor() as i32;
+            let y = ((1. - c.lat.to_radians().tan().asinh() / PI) / 2. * n as f64).floor() as i32;
+            visible.insert((x.clamp(0, n - 1) + world * n, y.clamp(0, n - 1)));
+        }
+    }
+    let around: BTreeSet<(i32, i32)> = visible
+        .iter()
+        .flat_map(|&(x, y)| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy))))
+        .map(|(x, y)| {
+            if repeats {
+                (x, y)
+            } else {
+                (x.rem_euclid(n), y)
+            }
+        })
+        .filter(|&(x, y)| 0 <= y && y < n && !visible.contains(&(x, y)))
+        .collect();
+
+    let tile = |(x, y): (i32, i32)| {
+        let (world, x) = (x.div_euclid(n), x.rem_euclid(n));
+        let nw = util::tile_to_coords(&util::TileCoords::new(x as u32, y as u32), zoom as f64);
+        let (sx, sy) = projection.coords_to_screen(&nw, center, scale, size);
+        let sx = sx + projection.world_offset(world, scale);
+        Tile {
+            zxy: (zoom as usize, x, y),
+            screenpos: (sx.round() as i32, sy.round() as i32),
+            world,
+            ..Default::default()
+        }
+    };
+    (
+        visible.iter().copied().map(tile).collect(),
+        around.into_iter().map(tile).collect(),
+    )
+}
+
+// Prepares tile geometry for a projection other than Web Mercator. It's cut
+// to the tile, or to its quadrant of an overzoomed source tile, so that
+// neighbors don't draw the same features twice. Long segments are split so
+// they can bend, and lines are broken where they leave the visible side of
+// the map. Polygons that are stroked rather than filled are cut like lines,
+// so they don't gain outlines along the tile edges.
+pub(crate) fn cut_for_projection(
+    geometry: Geometry,
+    t: &Tile,
+    extent: u32,
+    fill: bool,
+    projection: &dyn Projection,
+    center: &Coords,
+) -> Geometry {
+    let size = (extent as i32 >> t.overzoom).max(1);
+    let (qx, qy) = t.quadrant();
+    let rect = ((qx * size, qy * size), ((qx + 1) * size, (qy + 1) * size));
+    let step = (size / 16).max(1);
+    let visible =
+        |p: &(i32, i32)| projection.is_visible(&t.tile_point_to_coords(*p, extent), center);
+    let cut_lines = |lines: Vec<Vec<(i32, i32)>>| {
+        lines
+            .iter()
+            .flat_map(|line| geometry::clip_line(line, rect))
+            .flat_map(|line| {
+                geometry::densify(&line, step)
+                    .split(|p| !visible(p))
+                    .filter(|l| l.len() > 1)
+                    .map(|l| l.to_vec())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+
+    let ((x0, y0), (x1, y1)) = rect;
+    match geometry {
+        Geometry::MultiPoint(points) => Geometry::MultiPoint(
+            points
+                .into_iter()
+                .filter(|p| x0 <= p.0 && p.0 < x1 && y0 <= p.1 && p.1 < y1 && visible(p))
+                .collect(),
+        ),
+        Geometry::MultiPolygon(polygons) if fill => {
+            // Polygons are cut into pieces no bigger than a zoom 2 tile, so
+            // that no piece wraps around more than half of a globe's edge.
+            let k = 4 >> t.z().min(2);
+            let cell_size = size / k;
+            let cells = (0..k * k).map(|i| {
+                let (cx, cy) = (x0 + i % k * cell_size, y0 + i / k * cell_size);
+                ((cx, cy), (cx + cell_size, cy + cell_size))
+            });
+            Geometry::MultiPolygon(
+                cells
+                    .flat_map(|cell| {
+                        polygons.iter().filter_map(move |rings| {
+                            let mut rings = rings.iter().map(|ring| {
+                                geometry::densify(&geometry::clip_ring(ring, cell), step)
+                            });
+                            // Holes can't be left when the exterior ring isn't.
+                            let exterior = rings.next().filter(|ring| !ring.is_empty())?;
+                            Some(
+                                std::iter::once(exterior)
+                                    .chain(rings.filter(|ring| !ring.is_empty()))
+                                    .collect::<Vec<_>>(),
+                            )
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        geometry => Geometry::MultiLineString(cut_lines(geometry.into_lines())),
+    }
+}
+
+// The features that `style` draws from `tiles` within `radius` of `p` on
+// `screen`, nearest first. A feature that lies in several tiles, or is drawn
+// by several rules, is listed once.
+pub(crate) fn find_features(
+    tiles: &[Tile],
+    style: &Style,
+    screen: &impl ScreenSpace,
+    p: (f64, f64),
+    radius: f64,
+) -> Vec<Hit> {
+    let mut hits: Vec<Hit> = Vec::new();
+    for t in tiles {
+        let Some(vtile) = t.vtile.as_ref() else {
+            continue;
         };
-        features.into_iter().map(|s| s.to_string()).collect()
+        for rule in style.layers_at(t.z() as u32) {
+            let fill = rule.kind == DrawKind::Fill;
+            for layer in vtile
+                .layers
+                .iter()
+                .filter(|l| l.name == rule.layer && l.extent() > 0)
+            {
+                for feature in layer
+                    .features
+                    .iter()
+                    .map(|f| Feature::new(layer, f))
+                    .filter(|f| rule.matches(f))
+                {
+                    let Ok(geometry) = feature.geometry() else {
+                        continue;
+                    };
+                    let distance =
+                        match screen.geometry_to_screen(t, geometry, layer.extent(), fill) {
+                            Geometry::MultiPoint(points) => points
+                                .iter()
+                                .map(|q| (q.0 - p.0).hypot(q.1 - p.1))
+                                .fold(f64::INFINITY, f64::min),
+                            Geometry::MultiPolygon(polygons) if fill => polygons
+                                .iter()
+                                .map(|rings| {
+                                    if geometry::point_in_polygon(p, rings) {
+                                        0.
+                                    } else {
+                                        rings
+                                            .iter()
+                                            .map(|ring| geometry::distance_to_line(p, ring))
+                                            .fold(f64::INFINITY, f64::min)
+                                    }
+                                })
+                                .fold(f64::INFINITY, f64::min),
+                            geometry => geometry
+                                .into_lines()
+                                .iter()
+                                .map(|line| geometry::distance_to_line(p, line))
+                                .fold(f64::INFINITY, f64::min),
+                        };
+                    if distance > radius {
+                        continue;
+                    }
+
+                    let id = feature.id();
+                    let mut properties: Vec<(String, PropertyValue)> =
+                        feature.properties.into_iter().collect();
+                    properties.sort_by(|a, b| a.0.cmp(&b.0));
+                    let hit = Hit {
+                        layer: layer.name.clone(),
+                        id,
+                        properties,
+                        distance,
+                    };
+                    match hits.iter_mut().find(|h| {
+                        h.layer == hit.layer && h.id == hit.id && h.properties == hit.properties
+                    }) {
+                        Some(h) => h.distance = h.distance.min(distance),
+                        None => hits.push(hit),
+                    }
+                }
+            }
+        }
     }
+    hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    hits
+}
 
-    fn rectangles_intersect(r1: ((i32, i32), (i32, i32)), r2: ((i32, i32), (i32, i32))) -> bool {
-        (r1.0 .0 < r2.1 .0) && (r1.1 .0 > r2.0 .0) && (r1.0 .1 < r2.1 .1) && (r1.1 .1 > r2.0 .1)
+// Fills in a tile's geometry. Past the source's max zoom, tiles are cut out of
+// their ancestor at max zoom, and tiles that can't be loaded borrow the
+// nearest ancestor the source still has cached.
+fn load_tile(ts: &mut dyn TileSource, t: &mut Tile) {
+    let dz = (t.z() as u32).saturating_sub(ts.zoom_range().1);
+    match ts.get_tile(t.z() - dz as usize, t.x() >> dz, t.y() >> dz) {
+        Ok(tile) => t.overzoomed_from(tile),
+        Err(e) => {
+            if !matches!(e, TileSourceError::Loading(..)) {
+                error!("Could not get vector tile: {}", e);
+            }
+            if let Some(ancestor) = cached_ancestor(ts, t) {
+                t.overzoomed_from(ancestor);
+            }
+        }
     }
 }
 
+// Tells the source which tiles to load: the visible ones closest to the screen
+// center first, then the ones around them, then the children of the tiles in
+// the middle of the screen, which zooming in shows next.
+fn request_tiles(
+    ts: &mut dyn TileSource,
+    visible: &[Tile],
+    around: &[Tile],
+    screen: (i32, i32),
+    tile_size: i32,
+) {
+    let (w, h) = screen;
+    let distance = |t: &Tile| {
+        let dx = (t.screenpos.0 + tile_size / 2 - w / 2) as i64;
+        let dy = (t.screenpos.1 + tile_size / 2 - h / 2) as i64;
+        dx * dx + dy * dy
+    };
+    let mut visible: Vec<&Tile> = visible.iter().collect();
+    visible.sort_by_key(|t| distance(t));
+
+    let middle = ((w / 4, h / 4), (w - w / 4, h - h / 4));
+    let children = visible
+        .iter()
+        .filter(|t| {
+            let bot_r = (t.screenpos.0 + tile_size, t.screenpos.1 + tile_size);
+            util::rectangles_intersect((t.screenpos, bot_r), middle)
+        })
+        .flat_map(|t| {
+            let (z, x, y) = t.zxy;
+            [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| (z + 1, 2 * x + dx, 2 * y + dy))
+        });
+
+    // Tiles past max zoom are cut out of the same source tile.
+    let maxzoom = ts.zoom_range().1 as usize;
+    let mut wanted: Vec<(usize, i32, i32)> = Vec::new();
+    for (z, x, y) in visible
+        .iter()
+        .copied()
+        .chain(around)
+        .map(|t| t.zxy)
+        .chain(children)
+    {
+        let dz = z.saturating_sub(maxzoom);
+        let k = (z - dz, x >> dz, y >> dz);
+        if !wanted.contains(&k) {
+            wanted.push(k);
+        }
+    }
+    ts.request(&wanted);
+}
+
+fn cached_ancestor(ts: &mut dyn TileSource, t: &Tile) -> Option<Tile> {
+    (1..=t.z()).find_map(|dz| ts.cached_tile(t.z() - dz, t.x() >> dz, t.y() >> dz))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::tile::PropertyValue;
+    use crate::tilesource::{CachedTileSource, DirectoryTileSource, DummyTileSource};
+    use prost::Message;
+    use std::fmt::Write;
+
+    fn braille_renderer(res: (usize, usize)) -> BrailleRenderer {
+        let ts = Box::new(DummyTileSource::default());
+        BrailleRenderer::new(res, Coords::from_deg(0., 0.), ts)
+    }
+
+    #[test]
+    fn test_majority_color() {
+        let water = Rgb([176, 1, 20]);
+        let road = Rgb([241, 5, 37]);
+        assert_eq!(majority_color(vec![].into_iter()), None);
+        assert_eq!(
+            majority_color(vec![road, water, water].into_iter()),
+            Some(water)
+        );
+        assert_eq!(majority_color(vec![road, water].into_iter()), Some(road));
+    }
+
+    #[test]
+    fn test_to_ansi() {
+        let mut r = braille_renderer((4, 4));
+        r.buf[0][0] = Some(Rgb([255, 0, 0]));
+        r.buf[1][1] = Some(Rgb([255, 0, 0]));
+
+        assert_eq!(r.to_braille(), vec!["⠑⠀"]);
+        assert_eq!(
+            r.to_ansi(ColorMode::Ansi256),
+            vec!["\x1b[38;5;196m⠑\x1b[0m⠀"]
+        );
+        assert_eq!(
+            r.to_ansi(ColorMode::TrueColor),
+            vec!["\x1b[38;2;255;0;0m⠑\x1b[0m⠀"]
+        );
+    }
+
+    #[test]
+    fn test_place_labels() {
+        let label = |text: &str, col: i32, row: i32, priority: (usize, i64)| Label {
+            text: text.to_string(),
+            col,
+            row,
+            color: Rgb([253, 150, 147]),
+            priority,
+        };
+        let candidates = vec![
+            label("Lyon", 12, 3, (3, 4)),
+            label("France", 10, 3, (1, 1)),
+            label("Paris", 2, 1, (3, 1)),
+            label("Paris", 3, 1, (3, 1)),
+            label("Zurich", 37, 5, (3, 2)),
+            label("Geneva", 30, 9, (3, 3)),
+        ];
+
+        let placed = place_labels(candidates, 40, 6);
+        let texts: Vec<(&str, i32, i32)> = placed
+            .iter()
+            .map(|l| (l.text.as_str(), l.col, l.row))
+            .collect();
+        assert_eq!(
+            texts,
+            vec![("France", 10, 3), ("Paris", 2, 1), ("Zurich", 34, 5)]
+        );
+    }
+
+    #[test]
+    fn test_draw_tile_skips_bad_features() {
+        let feature = |geom_type: GeomType, geometry: Vec<u32>| vector_tile::tile::Feature {
+            r#type: Some(geom_type as i32),
+            geometry,
+            ..Default::default()
+        };
+        let layer = |name: &str, features| vector_tile::tile::Layer {
+            name: name.to_string(),
+            features,
+            ..Default::default()
+        };
+        let vtile = vector_tile::Tile {
+            layers: vec![
+                layer(
+                    "water",
+                    vec![
+                        feature(GeomType::Polygon, vec![9, 6]),
+                        feature(GeomType::Unknown, vec![9, 6, 12]),
+                        feature(
+                            GeomType::Polygon,
+                            vec![9, 0, 0, 26, 40, 0, 0, 40, 39, 0, 15],
+                        ),
+                    ],
+                ),
+                layer(
+                    "transportation",
+                    vec![feature(GeomType::Linestring, vec![9, 0, 0, 10, 40, 40, 15])],
+                ),
+                layer(
+                    "place",
+                    vec![feature(GeomType::Point, vec![9, 6, 6, 10, 2, 2])],
+                ),
+            ],
+        };
+        let t = Tile::from_proto(0, 0, 0, vtile);
+
+        let mut r = braille_renderer((40, 40));
+        r.draw_tile(&t);
+
+        // Only the valid water polygon made it to the screen.
+        assert!(r.buf.iter().flatten().any(|dot| dot.is_some()));
+        assert!(r
+            .buf
+            .iter()
+            .flatten()
+            .all(|dot| dot.is_none() || *dot == Some(Rgb([176, 1, 20]))));
+        assert_eq!(r.stats.tiles, 1);
+        assert_eq!(r.stats.dropped, 3);
+        assert_eq!(r.stats.features.get("water"), Some(&1));
+    }
+
+    // Serves a fixed set of tiles, all of which count as cached.
+    struct FixedTileSource {
+        tiles: HashMap<(usize, i32, i32), vector_tile::Tile>,
+        maxzoom: u32,
+    }
+
+    impl TileSource for FixedTileSource {
+        fn get_tile(
+            &mut self,
+            z: usize,
+            x: i32,
+            y: i32,
+        ) -> Result<Tile, crate::tilesource::TileSourceError> {
+            self.cached_tile(z, x, y)
+                .ok_or(crate::tilesource::TileSourceError::NotFound(z, x, y))
+        }
+
+        fn zoom_range(&self) -> (u32, u32) {
+            (0, self.maxzoom)
+        }
+
+        fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<Tile> {
+            let vtile = self.tiles.get(&(z, x, y))?.clone();
+            Some(Tile::from_proto(x, y, z, vtile))
+        }
+    }
+
+    #[test]
+    fn test_overzoom() {
+        // A horizontal line through the top quarter of the world tile.
+        let mut layer = crate::encoder::LayerBuilder::new("water");
+        layer.add_feature(
+            None,
+            &Geometry::MultiLineString(vec![vec![(0, 1024), (4096, 1024)]]),
+            &HashMap::new(),
+        );
+        let world = crate::encoder::TileBuilder::new().add_layer(layer).build();
+        let mut ts = FixedTileSource {
+            tiles: HashMap::from([((0, 0, 0), world)]),
+            maxzoom: 0,
+        };
+
+        // Past max zoom, 1/1/0 shows the top right quarter of 0/0/0, where
+        // the line runs through the middle.
+        let mut t = Tile {
+            zxy: (1, 1, 0),
+            screenpos: (-100, -240),
+            ..Default::default()
+        };
+        load_tile(&mut ts, &mut t);
+        assert_eq!((t.overzoom, t.quadrant()), (1, (1, 0)));
+
+        let mut r = braille_renderer((40, 40));
+        r.draw_tile(&t);
+        // Bresenham leaves out the last dot of a segment.
+        for x in 0..39 {
+            assert_eq!(r.buf[x][16], Some(Rgb([176, 1, 20])), "dot at x = {}", x);
+        }
+        assert_eq!(r.buf.iter().flatten().filter(|d| d.is_some()).count(), 39);
+
+        // Below max zoom, missing tiles fall back to a cached ancestor.
+        ts.maxzoom = 14;
+        let mut t = Tile {
+            zxy: (2, 1, 1),
+            ..Default::default()
+        };
+        load_tile(&mut ts, &mut t);
+        assert_eq!((t.overzoom, t.source_zxy()), (2, (0, 0, 0)));
+
+        let mut t = Tile {
+            zxy: (2, 1, 1),
+            ..Default::default()
+        };
+        ts.tiles.clear();
+        load_tile(&mut ts, &mut t);
+        assert!(t.vtile.is_none());
+    }
+
+    #[test]
+    fn test_features_at() {
+        let mut water = crate::encoder::LayerBuilder::new("water");
+        water.add_feature(
+            Some(1),
+            &Geometry::MultiPolygon(vec![vec![vec![
+                (0, 0),
+                (2048, 0),
+                (2048, 2048),
+                (0, 2048),
+                (0, 0),
+            ]]]),
+            &HashMap::from([(
+                "class".to_string(),
+                PropertyValue::String("ocean".to_string()),
+            )]),
+        );
+        let mut place = crate::encoder::LayerBuilder::new("place");
+        place.add_feature(
+            None,
+            &Geometry::MultiPoint(vec![(3072, 3072)]),
+            &HashMap::from([
+                (
+                    "name".to_string(),
+                    PropertyValue::String("Paris".to_string()),
+                ),
+                ("rank".to_string(), PropertyValue::Int(1)),
+            ]),
+        );
+        let world = crate::encoder::TileBuilder::new()
+            .add_layer(water)
+            .add_layer(place)
+            .build();
+        let ts = Box::new(FixedTileSource {
+            tiles: HashMap::from([((0, 0, 0), world)]),
+            maxzoom: 0,
+        });
+        // The world tile fills the screen, 8 tile units to a dot.
+        let mut r = BrailleRenderer::new((512, 512), Coords::from_deg(0., 0.), ts);
+
+        let hits = r.features_at((100., 100.), 4.);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].layer, "water");
+        assert_eq!(hits[0].id, Some(1));
+        assert_eq!(hits[0].distance, 0.);
+
+        // Near the edge of the polygon, and near the point.
+        let hits = r.features_at((259., 100.), 4.);
+        assert_eq!((hits[0].layer.as_str(), hits[0].distance), ("water", 3.));
+        let hits = r.features_at((386., 384.), 4.);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].distance, 2.);
+        assert_eq!(
+            hits[0].properties,
+            vec![
+                (
+                    "name".to_string(),
+                    PropertyValue::String("Paris".to_string())
+                ),
+                ("rank".to_string(), PropertyValue::Int(1)),
+            ]
+        );
+
+        assert!(r.features_at((450., 100.), 4.).is_empty());
+    }
+
+    #[test]
+    fn test_drag_and_zoom_at() {
+        let mut r = braille_renderer((200, 100));
+        let paris = Coords::from_deg(48.85, 2.35);
+        let near = |(x, y): (f64, f64), (px, py): (f64, f64)| (x - px).hypot(y - py) < 1e-6;
+
+        // The map follows the pointer.
+        r.drag(r.coords_to_screen(&paris), (50., 30.));
+        assert!(near(r.coords_to_screen(&paris), (50., 30.)));
+
+        // Zooming keeps the place under the pointer, and zooming back out
+        // returns to the same scale.
+        for _ in 0..7 {
+            r.zoom_at((50., 30.), true);
+        }
+        assert_eq!(r.zoom, 1);
+        assert!(near(r.coords_to_screen(&paris), (50., 30.)));
+        for _ in 0..7 {
+            r.zoom_at((50., 30.), false);
+        }
+        assert_eq!(r.zoom, 0);
+        assert!((r.rel_zoom - 2.).abs() < 1e-9);
+        assert!(near(r.coords_to_screen(&paris), (50., 30.)));
+
+        r.resize((80, 40));
+        assert_eq!(r.to_braille().len(), 10);
+    }
 
     #[test]
     fn test_screen_pos() {
@@ -824,4 +1846,271 @@ mod tests {
         //     assert_eq!(Tile::parse_command_integer(ci), expected);
         // }
     }
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+    // fixtures/tiles only goes up to zoom 1. Deeper zoom levels are cut out
+    // of those tiles.
+    fn fixture_tiles() -> DirectoryTileSource {
+        DirectoryTileSource::new(Path::new(FIXTURES).join("tiles"))
+    }
+
+    fn fixture_style() -> Style {
+        Style::from_file(&format!("{FIXTURES}/style.json")).unwrap()
+    }
+
+    fn update_snapshots() -> bool {
+        std::env::var_os("UPDATE_SNAPSHOTS").is_some()
+    }
+
+    // Compares `actual` with fixtures/snapshots/`name` and lists the lines
+    // that differ. UPDATE_SNAPSHOTS=1 stores `actual` as the new expectation.
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = Path::new(FIXTURES).join("snapshots").join(name);
+        if update_snapshots() {
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+            panic!(
+                "Could not read {}: {}; run with UPDATE_SNAPSHOTS=1 to create it",
+                path.display(),
+                e
+            )
+        });
+
+        let expected: Vec<&str> = expected.lines().collect();
+        let actual: Vec<&str> = actual.lines().collect();
+        let mut diff = String::new();
+        for i in 0..expected.len().max(actual.len()) {
+            let (e, a) = (expected.get(i), actual.get(i));
+            if e == a {
+                continue;
+            }
+            if let Some(e) = e {
+                writeln!(diff, "{:>4} - {}", i + 1, e).unwrap();
+            }
+            if let Some(a) = a {
+                writeln!(diff, "{:>4} + {}", i + 1, a).unwrap();
+            }
+        }
+        assert!(
+            diff.is_empty(),
+            "{} doesn't match (- expected, + actual):\n{}\
+             Run with UPDATE_SNAPSHOTS=1 if the change is intended.",
+            path.display(),
+            diff
+        );
+    }
+
+    // Like assert_snapshot, pixel by pixel. On a mismatch the actual image and
+    // a diff, with changed pixels in red over a faded copy, are written to the
+    // temp directory.
+    fn assert_image_snapshot(name: &str, actual: &RgbImage) {
+        let path = Path::new(FIXTURES).join("snapshots").join(name);
+        if update_snapshots() {
+            actual.save(&path).unwrap();
+            return;
+        }
+        let expected = image::open(&path)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Could not read {}: {}; run with UPDATE_SNAPSHOTS=1 to create it",
+                    path.display(),
+                    e
+                )
+            })
+            .to_rgb8();
+        assert_eq!(
+            expected.dimensions(),
+            actual.dimensions(),
+            "{} has a different size",
+            path.display()
+        );
+
+        let mut diff = RgbImage::new(actual.width(), actual.height());
+        let mut changed = 0;
+        for (x, y, p) in actual.enumerate_pixels() {
+            if p == expected.get_pixel(x, y) {
+                diff.put_pixel(x, y, Rgb(p.0.map(|c| c / 4)));
+            } else {
+                diff.put_pixel(x, y, Rgb([255, 0, 0]));
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            let dir = std::env::temp_dir().join("pandemic-snapshots");
+            std::fs::create_dir_all(&dir).unwrap();
+            actual.save(dir.join(name)).unwrap();
+            diff.save(dir.join(format!("diff-{name}"))).unwrap();
+            panic!(
+                "{} doesn't match: {} of {} pixels changed, see {} and diff-{} in {}. \
+                 Run with UPDATE_SNAPSHOTS=1 if the change is intended.",
+                path.display(),
+                changed,
+                actual.width() * actual.height(),
+                name,
+                name,
+                dir.display()
+            );
+        }
+    }
+
+    // (file, zoom, (lat, lon) of the center)
+    const SNAPSHOT_VIEWS: [(&str, u32, (f64, f64)); 4] = [
+        ("world", 0, (0., 0.)),
+        ("atlantic", 1, (30., -40.)),
+        ("paris-overzoomed", 3, (48., 2.)),
+        // Across the antimeridian.
+        ("pacific", 0, (10., 180.)),
+    ];
+
+    #[test]
+    fn test_braille_snapshots() {
+        for (name, zoom, (lat, lon)) in SNAPSHOT_VIEWS {
+            let ts = Box::new(fixture_tiles());
+            let mut r = BrailleRenderer::new((160, 96), Coords::from_deg(lat, lon), ts);
+            r.style = fixture_style();
+            r.zoom = zoom;
+            r.draw();
+            let actual = r.to_braille().join("\n") + "\n";
+            assert_snapshot(&format!("braille-{name}.txt"), &actual);
+        }
+    }
+
+    #[test]
+    fn test_projected_snapshots() {
+        for name in ["equirectangular", "robinson", "orthographic"] {
+            let ts = Box::new(fixture_tiles());
+            let mut r = BrailleRenderer::new((160, 96), Coords::from_deg(20., -30.), ts);
+            r.style = fixture_style();
+            r.projection = projection::by_name(name).unwrap();
+            r.draw();
+            let actual = r.to_braille().join("\n") + "\n";
+            assert_snapshot(&format!("braille-{name}.txt"), &actual);
+        }
+    }
+
+    #[test]
+    fn test_image_snapshots() {
+        for (name, zoom, (lat, lon)) in SNAPSHOT_VIEWS {
+            let ts = Box::new(fixture_tiles());
+            let mut r = ImageRenderer::new((160, 120), Coords::from_deg(lat, lon), ts);
+            r.style = fixture_style();
+            r.zoom = zoom;
+            r.draw();
+            assert_image_snapshot(&format!("image-{name}.png"), &r.img);
+        }
+    }
+
+    #[test]
+    fn test_render_past_max_zoom() {
+        // Like the render subcommand, which doesn't load tiles in the
+        // background.
+        let ts = Box::new(CachedTileSource::unbounded(fixture_tiles()));
+        let mut r = BrailleRenderer::new((160, 96), Coords::from_deg(48., 2.), ts);
+        r.style = fixture_style();
+        r.zoom = 3;
+        r.draw();
+        assert!(r.stats.overzoomed > 0);
+        assert!(r
+            .to_braille()
+            .iter()
+            .any(|line| line.chars().any(|c| c != '⠀')));
+    }
+
+    // Writes fixtures/tiles: an ocean with two continents cut out of it, a
+    // border and a few named places, at zoom 0 and 1. Run with
+    // `cargo test write_fixture_tiles -- --ignored`.
+    #[test]
+    #[ignore]
+    fn write_fixture_tiles() {
+        let ocean = [(-180., 85.), (180., 85.), (180., -85.), (-180., -85.)];
+        let west = [
+            (-125., 50.),
+            (-60., 55.),
+            (-35., -5.),
+            (-70., -55.),
+            (-80., 10.),
+            (-120., 30.),
+        ];
+        let east = [
+            (-10., 60.),
+            (60., 70.),
+            (140., 50.),
+            (120., -10.),
+            (40., -35.),
+            (10., 0.),
+            (-15., 30.),
+        ];
+        let border = [(40., -30.), (40., 20.), (50., 65.)];
+        let places = [
+            ("place", "city", "Atlanta", (-84.39, 33.75)),
+            ("place", "city", "Paris", (2.35, 48.86)),
+            ("place", "city", "Tokyo", (139.69, 35.68)),
+            ("water_name", "ocean", "Atlantic", (-40., 25.)),
+            ("water_name", "ocean", "Pacific", (-150., 0.)),
+        ];
+
+        let extent = 4096;
+        for (z, x, y) in [(0, 0, 0), (1, 0, 0), (1, 1, 0), (1, 0, 1), (1, 1, 1)] {
+            let project = |&(lon, lat): &(f64, f64)| {
+                let n = 2f64.powi(z as i32);
+                let tx = (lon + 180.) / 360. * n - x as f64;
+                let ty = (1. - lat.to_radians().tan().asinh() / PI) / 2. * n - y as f64;
+                (
+                    (tx * extent as f64).round() as i32,
+                    (ty * extent as f64).round() as i32,
+                )
+            };
+            let ring = |points: &[(f64, f64)]| {
+                let mut ring: Vec<(i32, i32)> = points.iter().map(project).collect();
+                ring.push(ring[0]);
+                ring
+            };
+            let props = |pairs: &[(&str, PropertyValue)]| -> HashMap<String, PropertyValue> {
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect()
+            };
+
+            let mut water = LayerBuilder::new("water").with_extent(extent);
+            water.add_feature(
+                Some(1),
+                &Geometry::MultiPolygon(vec![vec![ring(&ocean), ring(&west), ring(&east)]]),
+                &props(&[("class", PropertyValue::String("ocean".into()))]),
+            );
+            let mut boundary = LayerBuilder::new("boundary").with_extent(extent);
+            boundary.add_feature(
+                Some(2),
+                &Geometry::MultiLineString(vec![border.iter().map(project).collect()]),
+                &props(&[("admin_level", PropertyValue::Int(2))]),
+            );
+            let mut tile = TileBuilder::new().add_layer(water).add_layer(boundary);
+            for layer_name in ["place", "water_name"] {
+                let mut layer = LayerBuilder::new(layer_name).with_extent(extent);
+                for (i, (_, class, name, p)) in places
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (l, ..))| *l == layer_name)
+                {
+                    layer.add_feature(
+                        Some(10 + i as u64),
+                        &Geometry::MultiPoint(vec![project(p)]),
+                        &props(&[
+                            ("class", PropertyValue::String(class.to_string())),
+                            ("name", PropertyValue::String(name.to_string())),
+                        ]),
+                    );
+                }
+                tile = tile.add_layer(layer);
+            }
+
+            let path =
+                DirectoryTileSource::new(Path::new(FIXTURES).join("tiles")).tile_path(z, x, y);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, tile.build().encode_to_vec()).unwrap();
+        }
+    }
 }
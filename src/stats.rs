@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Counters kept by tile sources. Caching sources count their own requests
+// and hits and add up the rest from the sources they wrap.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TileStats {
+    pub requests: u64,
+    pub hits: u64,
+    pub fetched: u64,
+    pub failures: u64,
+    pub bytes: u64,
+    pub fetch_time: Duration,
+    pub decoded: u64,
+    pub decode_time: Duration,
+}
+
+// Counters for one pass of a renderer over the visible tiles.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RenderStats {
+    pub tiles: usize,
+    pub overzoomed: usize,
+    // Features drawn per source layer.
+    pub features: BTreeMap<String, usize>,
+    // Features skipped because of broken geometry.
+    pub dropped: usize,
+    pub points_before_simplify: usize,
+    pub points_after_simplify: usize,
+    pub draw_time: Duration,
+}
+
+pub fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let ret = f();
+    (ret, start.elapsed())
+}
+
+impl TileStats {
+    pub fn record_fetch(&mut self, bytes: usize, fetch_time: Duration) {
+        self.fetched += 1;
+        self.bytes += bytes as u64;
+        self.fetch_time += fetch_time;
+    }
+
+    pub fn record_decode(&mut self, decode_time: Duration) {
+        self.decoded += 1;
+        self.decode_time += decode_time;
+    }
+
+    // Counts a request and whether it failed.
+    pub fn record_result<T, E>(&mut self, res: &Result<T, E>) {
+        self.requests += 1;
+        if res.is_err() {
+            self.failures += 1;
+        }
+    }
+
+    // Adds the counters of a wrapped source, except its requests and hits,
+    // which are the wrapper's misses.
+    pub fn with_inner(&self, inner: &TileStats) -> TileStats {
+        TileStats {
+            requests: self.requests,
+            hits: self.hits + inner.hits,
+            fetched: self.fetched + inner.fetched,
+            failures: self.failures + inner.failures,
+            bytes: self.bytes + inner.bytes,
+            fetch_time: self.fetch_time + inner.fetch_time,
+            decoded: self.decoded + inner.decoded,
+            decode_time: self.decode_time + inner.decode_time,
+        }
+    }
+
+    // Sums the counters of sources working side by side.
+    pub fn add(&mut self, other: &TileStats) {
+        self.requests += other.requests;
+        *self = self.with_inner(other);
+    }
+}
+
+impl RenderStats {
+    pub fn drawn(&self) -> usize {
+        self.features.values().sum()
+    }
+
+    // Share of points left after simplification, 1 when nothing was simplified.
+    pub fn simplify_ratio(&self) -> f64 {
+        match self.points_before_simplify {
+            0 => 1.,
+            n => self.points_after_simplify as f64 / n as f64,
+        }
+    }
+}
+
+fn average_ms(total: Duration, n: u64) -> f64 {
+    match n {
+        0 => 0.,
+        n => total.as_secs_f64() * 1000. / n as f64,
+    }
+}
+
+impl fmt::Display for TileStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} hits, {} fetched ({:.0} KiB, {:.1} ms avg), {} failed, {} decoded ({:.1} ms avg)",
+            self.requests,
+            self.hits,
+            self.fetched,
+            self.bytes as f64 / 1024.,
+            average_ms(self.fetch_time, self.fetched),
+            self.failures,
+            self.decoded,
+            average_ms(self.decode_time, self.decoded),
+        )
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} tiles ({} overzoomed), {} features drawn, {} dropped, {:.0}% of points kept, {:.1} ms",
+            self.tiles,
+            self.overzoomed,
+            self.drawn(),
+            self.dropped,
+            self.simplify_ratio() * 100.,
+            self.draw_time.as_secs_f64() * 1000.,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_stats() {
+        let mut inner = TileStats {
+            requests: 3,
+            hits: 1,
+            ..Default::default()
+        };
+        inner.record_fetch(2048, Duration::from_millis(30));
+        inner.record_fetch(1024, Duration::from_millis(10));
+        inner.record_decode(Duration::from_millis(4));
+
+        let outer = TileStats {
+            requests: 10,
+            hits: 7,
+            ..Default::default()
+        };
+        let total = outer.with_inner(&inner);
+        assert_eq!((total.requests, total.hits, total.fetched), (10, 8, 2));
+        assert_eq!(
+            total.to_string(),
+            "10 requests, 8 hits, 2 fetched (3 KiB, 20.0 ms avg), 0 failed, 1 decoded (4.0 ms avg)"
+        );
+
+        let mut sum = inner.clone();
+        sum.add(&inner);
+        assert_eq!((sum.requests, sum.fetched, sum.bytes), (6, 4, 6144));
+    }
+}
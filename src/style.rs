@@ -0,0 +1,275 @@
+use crate::tile::{Feature, PropertyValue};
+use crate::tilesource::VectorLayer;
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use thiserror::Error;
+
+const DEFAULT_STYLE: &str = include_str!("../styles/default.json");
+const MAX_ZOOM: u32 = 24;
+
+// A map style is an ordered list of rules. Rules are drawn in the order they
+// appear, so later rules end up on top of earlier ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Style {
+    pub layers: Vec<LayerStyle>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayerStyle {
+    pub layer: String,
+    pub color: [u8; 3],
+    #[serde(default)]
+    pub minzoom: u32,
+    #[serde(default = "default_maxzoom")]
+    pub maxzoom: u32,
+    #[serde(default)]
+    pub kind: DrawKind,
+    #[serde(default)]
+    pub filter: Vec<Filter>,
+    #[serde(default)]
+    pub label: Option<LabelStyle>,
+}
+
+// Point features of a labelled rule get their name printed next to them.
+// Labels of classes listed earlier win over later ones, then lower `rank` wins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LabelStyle {
+    #[serde(default = "default_label_keys")]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub classes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawKind {
+    #[default]
+    Line,
+    Fill,
+}
+
+// All filters of a rule have to match for a feature to be drawn by it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Filter {
+    In {
+        key: String,
+        values: Vec<serde_json::Value>,
+    },
+    NotIn {
+        key: String,
+        values: Vec<serde_json::Value>,
+    },
+    Has {
+        key: String,
+    },
+    NotHas {
+        key: String,
+    },
+}
+
+fn default_maxzoom() -> u32 {
+    MAX_ZOOM
+}
+
+fn default_label_keys() -> Vec<String> {
+    vec![String::from("name:latin"), String::from("name")]
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        serde_json::from_str(DEFAULT_STYLE).expect("built-in style must be valid")
+    }
+}
+
+impl Style {
+    pub fn from_file(path: &str) -> Result<Self, StyleError> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn layers_at(&self, zoom: u32) -> impl Iterator<Item = &LayerStyle> {
+        self.layers.iter().filter(move |l| l.visible_at(zoom))
+    }
+
+    // Names of the source layers drawn at this zoom level, in draw order.
+    pub fn draw_order(&self, zoom: u32) -> Vec<String> {
+        let mut order: Vec<String> = Vec::new();
+        for l in self.layers_at(zoom) {
+            if !order.contains(&l.layer) {
+                order.push(l.layer.clone());
+            }
+        }
+        order
+    }
+
+    // Source layers the style draws but the tile source doesn't provide,
+    // usually a sign that the style was written for a different schema.
+    pub fn missing_layers(&self, available: &[VectorLayer]) -> Vec<&str> {
+        let mut missing: Vec<&str> = Vec::new();
+        for l in &self.layers {
+            if !available.iter().any(|v| v.id == l.layer) && !missing.contains(&l.layer.as_str()) {
+                missing.push(&l.layer);
+            }
+        }
+        missing
+    }
+}
+
+impl LayerStyle {
+    pub fn visible_at(&self, zoom: u32) -> bool {
+        self.minzoom <= zoom && zoom <= self.maxzoom
+    }
+
+    pub fn rgb(&self) -> Rgb<u8> {
+        Rgb(self.color)
+    }
+
+    pub fn matches(&self, feature: &Feature) -> bool {
+        self.filter.iter().all(|f| f.matches(feature))
+    }
+}
+
+impl LabelStyle {
+    pub fn text(&self, feature: &Feature) -> Option<String> {
+        self.keys
+            .iter()
+            .find_map(|k| feature.get_str(k))
+            .map(|s| s.to_string())
+    }
+
+    // Lower is more important.
+    pub fn priority(&self, feature: &Feature) -> (usize, i64) {
+        let class = feature
+            .get_str("class")
+            .and_then(|c| self.classes.iter().position(|x| x == c))
+            .unwrap_or(self.classes.len());
+        let rank = feature.get_i64("rank").unwrap_or(i64::MAX);
+        (class, rank)
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, feature: &Feature) -> bool {
+        match self {
+            Filter::In { key, values } => feature
+                .get(key)
+                .is_some_and(|v| values.iter().any(|x| value_eq(v, x))),
+            Filter::NotIn { key, values } => feature
+                .get(key)
+                .is_none_or(|v| !values.iter().any(|x| value_eq(v, x))),
+            Filter::Has { key } => feature.get(key).is_some(),
+            Filter::NotHas { key } => feature.get(key).is_none(),
+        }
+    }
+}
+
+fn value_eq(v: &PropertyValue, x: &serde_json::Value) -> bool {
+    match x {
+        serde_json::Value::String(s) => v.as_str() == Some(s.as_str()),
+        serde_json::Value::Bool(b) => v.as_bool() == Some(*b),
+        serde_json::Value::Number(n) => v.as_f64().is_some() && v.as_f64() == n.as_f64(),
+        _ => false,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StyleError {
+    #[error("could not read style file")]
+    IoError(#[from] std::io::Error),
+    #[error("could not parse style file")]
+    SerdeJsonError(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_style() {
+        let style = Style::default();
+        assert_eq!(style.draw_order(0)[0], "water");
+        assert!(!style.draw_order(0).contains(&String::from("building")));
+        assert!(style.draw_order(14).contains(&String::from("building")));
+    }
+
+    #[test]
+    fn test_missing_layers() {
+        let layer = |id: &str| VectorLayer {
+            id: id.to_string(),
+            fields: Default::default(),
+            minzoom: None,
+            maxzoom: None,
+            description: None,
+        };
+        let style: Style = serde_json::from_str(
+            r#"{"layers": [
+                {"layer": "water", "color": [0, 0, 255]},
+                {"layer": "road", "color": [255, 0, 0]},
+                {"layer": "road", "color": [255, 0, 0], "minzoom": 10}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            style.missing_layers(&[layer("water"), layer("place")]),
+            vec!["road"]
+        );
+    }
+
+    #[test]
+    fn test_label_priority() {
+        let label: LabelStyle =
+            serde_json::from_str(r#"{"classes": ["country", "city"]}"#).unwrap();
+        let layer = Default::default();
+        let raw = Default::default();
+        let feature = |name: &str, class: &str, rank: i64| Feature {
+            layer: &layer,
+            feature: &raw,
+            properties: [
+                (
+                    String::from("name"),
+                    PropertyValue::String(name.to_string()),
+                ),
+                (
+                    String::from("class"),
+                    PropertyValue::String(class.to_string()),
+                ),
+                (String::from("rank"), PropertyValue::Int(rank)),
+            ]
+            .into(),
+        };
+
+        let france = feature("France", "country", 1);
+        let paris = feature("Paris", "city", 1);
+        let lyon = feature("Lyon", "city", 4);
+        let village = feature("Giverny", "village", 1);
+        assert_eq!(label.text(&paris), Some(String::from("Paris")));
+        assert!(label.priority(&france) < label.priority(&paris));
+        assert!(label.priority(&paris) < label.priority(&lyon));
+        assert!(label.priority(&lyon) < label.priority(&village));
+    }
+
+    #[test]
+    fn test_filters() {
+        let layer = Default::default();
+        let raw = Default::default();
+        let feature = Feature {
+            layer: &layer,
+            feature: &raw,
+            properties: [(String::from("admin_level"), PropertyValue::Int(2))].into(),
+        };
+        let filter: Filter =
+            serde_json::from_str(r#"{"op": "in", "key": "admin_level", "values": [2, 4]}"#)
+                .unwrap();
+        assert!(filter.matches(&feature));
+
+        let filter: Filter =
+            serde_json::from_str(r#"{"op": "not_in", "key": "admin_level", "values": [2]}"#)
+                .unwrap();
+        assert!(!filter.matches(&feature));
+
+        let filter: Filter = serde_json::from_str(r#"{"op": "has", "key": "class"}"#).unwrap();
+        assert!(!filter.matches(&feature));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::city::City;
+use crate::color::Color;
+use crate::geometry::Geometry;
+use crate::projection::{Projection, WebMercator};
+use crate::renderer::{self, Direction, Hit, Renderer, ScreenSpace, View, MAX_OVERZOOM};
+use crate::stats::{self, RenderStats, TileStats};
+use crate::style::{DrawKind, LayerStyle, Style};
+use crate::tile::{self, Feature, Tile};
+use crate::tilesource::TileSource;
+use crate::util::{self, Coords};
+use image::Rgb;
+use simplify_polyline as sp;
+use std::f64::consts::PI;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const CITY_RADIUS: f64 = 4.;
+const CUBE_SIZE: f64 = 3.;
+const FONT_SIZE: u32 = 10;
+
+// Draws the map as vector paths, one group per source layer in the order the
+// style first draws them, with the board's cities on top.
+pub struct SvgRenderer {
+    width: usize,
+    height: usize,
+    pub center: Coords,
+    pub zoom: u32,
+    pub projection: Box<dyn Projection>,
+    tilesource: Box<dyn TileSource>,
+    rel_zoom: f64,
+    pub simplify: bool,
+    pub tolerance: f64,
+    pub high_quality: bool,
+    pub style: Style,
+    pub stats: RenderStats,
+    // Cities drawn over the map with their connections. Cities without a
+    // location, or on the far side of the globe, are left out.
+    pub board: Vec<City>,
+    // In Web Mercator, each tile's paths are clipped to the tile, which hides
+    // the buffer neighboring tiles draw as well, and cuts overzoomed tiles to
+    // size.
+    clip_paths: Vec<String>,
+    groups: Vec<(String, Vec<String>)>,
+    overlay: Vec<String>,
+    // Layer of the features being drawn.
+    layer: String,
+}
+
+impl Renderer for SvgRenderer {
+    fn new(res: (usize, usize), center: Coords, tilesource: Box<dyn TileSource>) -> Self {
+        SvgRenderer {
+            width: res.0,
+            height: res.1,
+            center: Coords::from_deg(center.lat, util::normalize_lon(center.lon)),
+            zoom: 0,
+            projection: Box::new(WebMercator),
+            tilesource,
+            rel_zoom: 1.,
+            simplify: false,
+            tolerance: 1.,
+            high_quality: false,
+            style: Style::default(),
+            stats: RenderStats::default(),
+            board: Vec::new(),
+            clip_paths: Vec::new(),
+            groups: Vec::new(),
+            overlay: Vec::new(),
+            layer: String::new(),
+        }
+    }
+    fn zoom_in(&mut self) {
+        self.zoom = std::cmp::min(self.zoom + 1, self.tilesource.zoom_range().1 + MAX_OVERZOOM);
+    }
+    fn zoom_out(&mut self) {
+        if self.zoom > self.tilesource.zoom_range().0 {
+            self.zoom -= 1;
+        }
+    }
+    fn pan(&mut self, d: Direction) {
+        let scaler = 2f64.powf(self.zoom as f64);
+        match d {
+            Direction::RIGHT => {
+                self.center.lon = util::normalize_lon(self.center.lon + 10. / scaler);
+            }
+            Direction::LEFT => {
+                self.center.lon = util::normalize_lon(self.center.lon - 10. / scaler);
+            }
+            Direction::UP => {
+                self.center.lat = (self.center.lat + 10. / scaler).min(self.projection.max_lat());
+            }
+            Direction::DOWN => {
+                self.center.lat = (self.center.lat - 10. / scaler).max(-self.projection.max_lat());
+            }
+        };
+    }
+
+    fn clear_buf(&mut self) {
+        self.clip_paths.clear();
+        self.groups.clear();
+        self.overlay.clear();
+    }
+
+    fn draw(&mut self) {
+        self.clear_buf();
+        self.stats = RenderStats::default();
+
+        let ((), draw_time) = stats::timed(|| {
+            let tiles: Vec<tile::Tile> = self.visible_tiles();
+            info!("There are {} visible tiles", tiles.len());
+
+            for t in &tiles {
+                self.draw_tile(t);
+            }
+            self.draw_board();
+        });
+        self.stats.draw_time = draw_time;
+    }
+    fn draw_tile(&mut self, t: &tile::Tile) {
+        self.stats.tiles += 1;
+        if t.overzoom > 0 {
+            self.stats.overzoomed += 1;
+        }
+        let size = 256. * self.rel_zoom;
+        if self.projection.is_web_mercator() {
+            self.clip_paths.push(format!(
+                r#"<clipPath id="{}"><rect x="{}" y="{}" width="{size}" height="{size}"/></clipPath>"#,
+                clip_id(t),
+                t.screenpos.0,
+                t.screenpos.1,
+            ));
+        }
+
+        if let Some(vtile) = t.vtile.as_ref() {
+            let rules: Vec<LayerStyle> = self.style.layers_at(t.z() as u32).cloned().collect();
+            for rule in &rules {
+                let color = rule.rgb();
+
+                for layer in vtile.layers.iter().filter(|l| l.name == rule.layer) {
+                    let extent = layer.extent();
+                    if extent == 0 {
+                        warn!("Skipping layer {} with zero extent", layer.name);
+                        continue;
+                    }
+                    self.layer = layer.name.clone();
+
+                    for feature in layer
+                        .features
+                        .iter()
+                        .map(|f| Feature::new(layer, f))
+                        .filter(|f| rule.matches(f))
+                    {
+                        let geometry = match feature.geometry() {
+                            Ok(geometry) => geometry,
+                            Err(e) => {
+                                self.stats.dropped += 1;
+                                warn!(
+                                    "Skipping feature {:?} of layer {}: {}",
+                                    feature.id(),
+                                    layer.name,
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+                        *self.stats.features.entry(layer.name.clone()).or_default() += 1;
+                        let geometry =
+                            self.cut_for_view(t, geometry, extent, rule.kind == DrawKind::Fill);
+
+                        match geometry {
+                            Geometry::MultiPoint(points) => {
+                                for p in points {
+                                    let p = self.tile_point_to_screen_space(t, p, extent);
+                                    let (x, y) = (p.x as f64, p.y as f64);
+                                    if self.projection.is_web_mercator()
+                                        && !self.point_within_tile_bounds(t, (x, y))
+                                    {
+                                        continue;
+                                    }
+                                    let mut element = format!(
+                                        r#"<circle cx="{x:.1}" cy="{y:.1}" r="1.5" fill="{}"/>"#,
+                                        hex(color)
+                                    );
+                                    if let Some(text) =
+                                        rule.label.as_ref().and_then(|l| l.text(&feature))
+                                    {
+                                        element.push_str(&text_element(x, y - 3., &text, color));
+                                    }
+                                    self.push(element);
+                                }
+                            }
+                            Geometry::MultiPolygon(polygons) if rule.kind == DrawKind::Fill => {
+                                for rings in polygons {
+                                    let rings: Vec<Vec<sp::Point<f32>>> = rings
+                                        .iter()
+                                        .map(|ring| self.ring_to_screen_space(t, ring, extent))
+                                        .collect();
+                                    self.fill_polygon(t, &rings, color);
+                                }
+                            }
+                            geometry => {
+                                let mut lines: Vec<Vec<sp::Point<f32>>> = geometry
+                                    .into_lines()
+                                    .into_iter()
+                                    .map(|line| {
+                                        line.into_iter()
+                                            .map(|p| self.tile_point_to_screen_space(t, p, extent))
+                                            .collect()
+                                    })
+                                    .collect();
+
+                                if self.simplify {
+                                    for line in lines.iter_mut() {
+                                        let before = line.len();
+                                        *line =
+                                            sp::simplify(line, self.tolerance, self.high_quality);
+                                        self.stats.points_before_simplify += before;
+                                        self.stats.points_after_simplify += line.len();
+                                    }
+                                }
+
+                                let d = path_data(&lines, false);
+                                if !d.is_empty() {
+                                    self.push(format!(
+                                        r#"<path d="{d}" fill="none" stroke="{}"{}/>"#,
+                                        hex(color),
+                                        self.clip_path(t)
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn draw_line(&mut self, t: &Tile, p: (i32, i32), q: (i32, i32), extent: u32, color: Rgb<u8>) {
+        self.push(format!(
+            r#"<path d="M{} {}L{} {}" fill="none" stroke="{}"{}/>"#,
+            p.0,
+            p.1,
+            q.0,
+            q.1,
+            hex(color),
+            self.clip_path(t)
+        ));
+    }
+    // Holes are left out by the even-odd rule, as rings don't overlap otherwise.
+    fn fill_polygon(&mut self, t: &Tile, rings: &[Vec<sp::Point<f32>>], color: Rgb<u8>) {
+        let d = path_data(rings, true);
+        if !d.is_empty() {
+            self.push(format!(
+                r#"<path d="{d}" fill="{}" fill-rule="evenodd"{}/>"#,
+                hex(color),
+                self.clip_path(t)
+            ));
+        }
+    }
+    fn visible_tiles(&mut self) -> Vec<tile::Tile> {
+        renderer::load_visible_tiles(
+            self.tilesource.as_mut(),
+            self.projection.as_ref(),
+            &self.center,
+            self.zoom,
+            (self.width, self.height),
+            256.0 * self.rel_zoom,
+        )
+    }
+    fn poll_tiles(&mut self) -> bool {
+        self.tilesource.poll()
+    }
+    fn tile_stats(&self) -> TileStats {
+        self.tilesource.stats()
+    }
+    fn features_at(&mut self, p: (f64, f64), radius: f64) -> Vec<Hit> {
+        let tiles = self.visible_tiles();
+        renderer::find_features(&tiles, &self.style, &*self, p, radius)
+    }
+}
+
+impl ScreenSpace for SvgRenderer {
+    fn view(&self) -> View<'_> {
+        View {
+            projection: self.projection.as_ref(),
+            center: &self.center,
+            zoom: self.zoom,
+            rel_zoom: self.rel_zoom,
+            size: (self.width, self.height),
+        }
+    }
+}
+
+impl SvgRenderer {
+    fn point_within_tile_bounds(&self, t: &Tile, p: (f64, f64)) -> bool {
+        let size = 256. * self.rel_zoom;
+        let (x0, y0) = (t.screenpos.0 as f64, t.screenpos.1 as f64);
+        x0 <= p.0 && p.0 < x0 + size && y0 <= p.1 && p.1 < y0 + size
+    }
+
+    // Reprojected geometry was already cut to the tile, and tiles are no
+    // longer squares on screen.
+    fn clip_path(&self, t: &Tile) -> String {
+        if self.projection.is_web_mercator() {
+            format!(r#" clip-path="url(#{})""#, clip_id(t))
+        } else {
+            String::new()
+        }
+    }
+
+    fn push(&mut self, element: String) {
+        match self
+            .groups
+            .iter_mut()
+            .find(|(layer, _)| *layer == self.layer)
+        {
+            Some((_, elements)) => elements.push(element),
+            None => self.groups.push((self.layer.clone(), vec![element])),
+        }
+    }
+
+    // Connections first, so the cities are drawn over them. Connections across
+    // the antimeridian leave the screen on one side and come back on the other.
+    fn draw_board(&mut self) {
+        let located: Vec<(&City, (f64, f64))> = self
+            .board
+            .iter()
+            .filter_map(|c| {
+                let (lat, lon) = c.location?;
+                let location = Coords::from_deg(lat, lon);
+                self.projection
+                    .is_visible(&location, &self.center)
+                    .then(|| (c, self.coords_to_screen(&location)))
+            })
+            .collect();
+        let world_size = 256. * self.rel_zoom * 2f64.powf(self.zoom as f64);
+
+        let mut overlay = Vec::new();
+        for (city, p) in &located {
+            for (_, q) in located
+                .iter()
+                .filter(|(n, _)| city.neighbors.contains(&n.name) && city.name < n.name)
+            {
+                let wrap = if q.0 - p.0 > world_size / 2. {
+                    -world_size
+                } else if p.0 - q.0 > world_size / 2. {
+                    world_size
+                } else {
+                    0.
+                };
+                overlay.push(line_element(*p, (q.0 + wrap, q.1)));
+                if wrap != 0. {
+                    overlay.push(line_element((p.0 - wrap, p.1), *q));
+                }
+            }
+        }
+
+        for (city, (x, y)) in &located {
+            let color = Rgb::from(city.color);
+            overlay.push(format!(
+                r#"<circle cx="{x:.1}" cy="{y:.1}" r="{CITY_RADIUS}" fill="{}" stroke="black"/>"#,
+                hex(color)
+            ));
+            if city.has_research_center {
+                overlay.push(format!(
+                    r#"<rect x="{:.1}" y="{:.1}" width="{s}" height="{s}" fill="white" stroke="black"/>"#,
+                    x - CITY_RADIUS / 2.,
+                    y - CITY_RADIUS / 2.,
+                    s = CITY_RADIUS,
+                ));
+            }
+            overlay.push(text_element(
+                *x,
+                y - CITY_RADIUS - 2.,
+                &city.name,
+                Rgb([0, 0, 0]),
+            ));
+
+            // A row of disease cubes under the city.
+            let cubes = [Color::Blue, Color::Yellow, Color::Red, Color::Black]
+                .into_iter()
+                .flat_map(|c| {
+                    let n = city.infections.get(&c).copied().unwrap_or(0);
+                    std::iter::repeat_n(c, n as usize)
+                });
+            for (i, c) in cubes.enumerate() {
+                overlay.push(format!(
+                    r#"<rect x="{:.1}" y="{:.1}" width="{CUBE_SIZE}" height="{CUBE_SIZE}" fill="{}"/>"#,
+                    x - CITY_RADIUS + i as f64 * (CUBE_SIZE + 1.),
+                    y + CITY_RADIUS + 1.,
+                    hex(Rgb::from(c))
+                ));
+            }
+        }
+        self.overlay = overlay;
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(
+            s,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = self.width,
+            h = self.height
+        );
+        s.push_str("<defs>\n");
+        for clip_path in &self.clip_paths {
+            let _ = writeln!(s, "{}", clip_path);
+        }
+        s.push_str("</defs>\n");
+        for (layer, elements) in &self.groups {
+            let _ = writeln!(s, r#"<g id="{}">"#, escape(layer));
+            for element in elements {
+                let _ = writeln!(s, "{}", element);
+            }
+            s.push_str("</g>\n");
+        }
+        if !self.overlay.is_empty() {
+            s.push_str("<g id=\"board\">\n");
+            for element in &self.overlay {
+                let _ = writeln!(s, "{}", element);
+            }
+            s.push_str("</g>\n");
+        }
+        s.push_str("</svg>\n");
+        s
+    }
+
+    // Writes the last drawn frame.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_svg())
+    }
+}
+
+fn clip_id(t: &Tile) -> String {
+    let (z, x, y) = t.zxy;
+    format!("tile-{}-{}-{}", z, x, y)
+}
+
+fn hex(c: Rgb<u8>) -> String {
+    let [r, g, b] = c.0;
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn path_data(lines: &[Vec<sp::Point<f32>>], close: bool) -> String {
+    let mut d = String::new();
+    for line in lines.iter().filter(|l| l.len() > 1) {
+        for (i, p) in line.iter().enumerate() {
+            let _ = write!(d, "{}{:.1} {:.1}", if i == 0 { 'M' } else { 'L' }, p.x, p.y);
+        }
+        if close {
+            d.push('Z');
+        }
+    }
+    d
+}
+
+fn line_element(p: (f64, f64), q: (f64, f64)) -> String {
+    format!(
+        r##"<path d="M{:.1} {:.1}L{:.1} {:.1}" stroke="#555555" stroke-width="1.5"/>"##,
+        p.0, p.1, q.0, q.1
+    )
+}
+
+fn text_element(x: f64, y: f64, text: &str, color: Rgb<u8>) -> String {
+    format!(
+        r#"<text x="{x:.1}" y="{y:.1}" font-size="{FONT_SIZE}" font-family="sans-serif" text-anchor="middle" fill="{}">{}</text>"#,
+        hex(color),
+        escape(text)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesource::TileSourceError;
+    use crate::vector_tile;
+    use crate::vector_tile::tile::GeomType;
+
+    // Serves the same tile for every position.
+    struct OneTileSource(vector_tile::Tile);
+
+    impl TileSource for OneTileSource {
+        fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<Tile, TileSourceError> {
+            Ok(Tile::from_proto(x, y, z, self.0.clone()))
+        }
+    }
+
+    #[test]
+    fn test_svg_renderer() {
+        let feature = |geom_type: GeomType, geometry: Vec<u32>| vector_tile::tile::Feature {
+            r#type: Some(geom_type as i32),
+            geometry,
+            ..Default::default()
+        };
+        let layer = |name: &str, features| vector_tile::tile::Layer {
+            name: name.to_string(),
+            features,
+            extent: Some(256),
+            ..Default::default()
+        };
+        // A square with a square hole.
+        let water = feature(
+            GeomType::Polygon,
+            vec![
+                9, 0, 0, 26, 200, 0, 0, 200, 199, 0, 15, 9, 40, 159, 26, 0, 40, 40, 0, 0, 39, 15,
+            ],
+        );
+        let vtile = vector_tile::Tile {
+            layers: vec![layer("water", vec![water])],
+        };
+
+        let center = Coords::from_deg(0., 0.);
+        let mut r = SvgRenderer::new((256, 256), center, Box::new(OneTileSource(vtile)));
+        r.board = vec![
+            City {
+                name: String::from("Lagos & Co"),
+                location: Some((6.52, 3.38)),
+                neighbors: vec![String::from("Kinshasa")],
+                ..Default::default()
+            },
+            City {
+                name: String::from("Kinshasa"),
+                location: Some((-4.44, 15.27)),
+                neighbors: vec![String::from("Lagos & Co")],
+                has_research_center: true,
+                ..Default::default()
+            },
+            City {
+                name: String::from("Atlantis"),
+                ..Default::default()
+            },
+        ];
+        r.draw();
+        let svg = r.to_svg();
+
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(r#"<clipPath id="tile-0-0-0"><rect x="0" y="0""#));
+        assert!(svg.contains(
+            r##"<g id="water">
+<path d="M0.0 0.0L100.0 0.0L100.0 100.0L0.0 100.0L0.0 0.0ZM20.0 20.0L20.0 40.0L40.0 40.0L40.0 20.0L20.0 20.0Z" fill="#b00114" fill-rule="evenodd" clip-path="url(#tile-0-0-0)"/>
+</g>"##
+        ));
+        assert_eq!(r.stats.features.get("water"), Some(&1));
+
+        // Both located cities and the one connection between them.
+        assert_eq!(svg.matches("<circle").count(), 2);
+        assert_eq!(svg.matches(r#"stroke-width="1.5""#).count(), 1);
+        assert!(svg.contains(">Lagos &amp; Co</text>"));
+        assert!(!svg.contains("Atlantis"));
+        assert!(svg.contains(r#"fill="white" stroke="black""#));
+
+        assert_eq!(r.coords_to_screen(&center), (128., 128.));
+    }
+}
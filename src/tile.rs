@@ -1,13 +1,22 @@
-use crate::util;
+use crate::geometry::Geometry;
+use crate::util::{self, Coords};
 use crate::vector_tile::{self, tile::GeomType};
 use std::collections::HashMap;
 use std::f64::consts::PI;
+use thiserror::Error;
 
 #[derive(Debug, Default, Clone)]
 pub struct Tile {
     pub zxy: (usize, i32, i32),
     pub screenpos: (i32, i32),
     pub vtile: Option<vector_tile::Tile>,
+    // When non-zero, vtile holds the ancestor this many levels up, of which
+    // only the quadrant covering this tile is drawn, scaled up.
+    pub overzoom: u32,
+    // Which copy of the world the tile is drawn in, when a projection repeats
+    // the map east and west: 0 is the one the center is in, 1 the next one
+    // east. Web Mercator tiles are placed by screenpos instead.
+    pub world: i32,
 }
 
 #[derive(Debug)]
@@ -27,6 +36,24 @@ impl BoundingBox {
     }
 }
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum GeometryError {
+    #[error("unknown command id {0} at offset {1}")]
+    UnknownCommand(u32, usize),
+    #[error("command at offset {0} is missing parameters")]
+    TruncatedParameters(usize),
+    #[error("ClosePath at offset {0} does not have a count of 1")]
+    InvalidClosePathCount(usize),
+    #[error("ClosePath in a {0:?} geometry")]
+    ClosePathOutsidePolygon(GeomType),
+    #[error("LineTo in a point geometry")]
+    LineToInPoint,
+    #[error("coordinates overflow")]
+    CoordinateOverflow,
+    #[error("unknown geometry type")]
+    UnknownGeometryType,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum GeometryCommand {
     MoveTo(i32, i32), // (dx, dy)
@@ -34,6 +61,157 @@ pub enum GeometryCommand {
     ClosePath,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+// A feature together with the layer it belongs to, with its tags decoded
+// against the layer's key and value tables.
+#[derive(Debug, Clone)]
+pub struct Feature<'a> {
+    pub layer: &'a vector_tile::tile::Layer,
+    pub feature: &'a vector_tile::tile::Feature,
+    pub properties: HashMap<String, PropertyValue>,
+}
+
+impl PropertyValue {
+    pub fn from_proto(v: &vector_tile::tile::Value) -> Option<Self> {
+        if let Some(s) = &v.string_value {
+            Some(PropertyValue::String(s.clone()))
+        } else if let Some(f) = v.float_value {
+            Some(PropertyValue::Float(f))
+        } else if let Some(d) = v.double_value {
+            Some(PropertyValue::Double(d))
+        } else if let Some(i) = v.int_value {
+            Some(PropertyValue::Int(i))
+        } else if let Some(u) = v.uint_value {
+            Some(PropertyValue::UInt(u))
+        } else if let Some(i) = v.sint_value {
+            Some(PropertyValue::SInt(i))
+        } else {
+            v.bool_value.map(PropertyValue::Bool)
+        }
+    }
+
+    pub fn to_proto(&self) -> vector_tile::tile::Value {
+        let mut v = vector_tile::tile::Value::default();
+        match self {
+            PropertyValue::String(s) => v.string_value = Some(s.clone()),
+            PropertyValue::Float(f) => v.float_value = Some(*f),
+            PropertyValue::Double(d) => v.double_value = Some(*d),
+            PropertyValue::Int(i) => v.int_value = Some(*i),
+            PropertyValue::UInt(u) => v.uint_value = Some(*u),
+            PropertyValue::SInt(i) => v.sint_value = Some(*i),
+            PropertyValue::Bool(b) => v.bool_value = Some(*b),
+        }
+        v
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            PropertyValue::Float(f) => Some(f as f64),
+            PropertyValue::Double(d) => Some(d),
+            PropertyValue::Int(i) | PropertyValue::SInt(i) => Some(i as f64),
+            PropertyValue::UInt(u) => Some(u as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            PropertyValue::Int(i) | PropertyValue::SInt(i) => Some(i),
+            PropertyValue::UInt(u) => i64::try_from(u).ok(),
+            PropertyValue::Float(_) | PropertyValue::Double(_) => {
+                self.as_f64().filter(|f| f.fract() == 0.).map(|f| f as i64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            PropertyValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PropertyValue::String(s) => write!(f, "{}", s),
+            PropertyValue::Float(x) => write!(f, "{}", x),
+            PropertyValue::Double(x) => write!(f, "{}", x),
+            PropertyValue::Int(x) | PropertyValue::SInt(x) => write!(f, "{}", x),
+            PropertyValue::UInt(x) => write!(f, "{}", x),
+            PropertyValue::Bool(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl<'a> Feature<'a> {
+    pub fn new(
+        layer: &'a vector_tile::tile::Layer,
+        feature: &'a vector_tile::tile::Feature,
+    ) -> Self {
+        Feature {
+            layer,
+            feature,
+            properties: Tile::decode_properties(layer, feature),
+        }
+    }
+
+    pub fn id(&self) -> Option<u64> {
+        self.feature.id
+    }
+
+    pub fn geom_type(&self) -> GeomType {
+        self.feature.r#type()
+    }
+
+    pub fn commands(&self) -> Result<Vec<GeometryCommand>, GeometryError> {
+        Tile::decode_geometry(self.geom_type(), &self.feature.geometry)
+    }
+
+    pub fn geometry(&self) -> Result<Geometry, GeometryError> {
+        Geometry::from_commands(self.geom_type(), &self.commands()?)
+    }
+
+    pub fn extent(&self) -> u32 {
+        self.layer.extent()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&PropertyValue> {
+        self.properties.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(|v| v.as_f64())
+    }
+
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(|v| v.as_i64())
+    }
+}
+
 impl Tile {
     pub fn from_proto(x: i32, y: i32, z: usize, vtile: vector_tile::Tile) -> Self {
         Tile {
@@ -53,15 +231,47 @@ impl Tile {
         self.zxy.0
     }
 
-    pub fn parse_geometry(geometry: &Vec<u32>) -> Vec<GeometryCommand> {
+    // The tile that vtile was actually decoded from.
+    pub fn source_zxy(&self) -> (usize, i32, i32) {
+        let dz = self.overzoom;
+        (self.z() - dz as usize, self.x() >> dz, self.y() >> dz)
+    }
+
+    // Position of this tile inside its source tile, counted in tiles of this
+    // tile's zoom level.
+    pub fn quadrant(&self) -> (i32, i32) {
+        let mask = (1 << self.overzoom) - 1;
+        (self.x() & mask, self.y() & mask)
+    }
+
+    // Takes a tile's geometry to use for this tile, which lies `overzoom`
+    // levels below it.
+    pub fn overzoomed_from(&mut self, ancestor: Tile) {
+        self.overzoom = (self.z() - ancestor.z()) as u32;
+        self.vtile = ancestor.vtile;
+    }
+
+    // Splits a geometry into commands, checking command ids and that every
+    // command comes with all of its parameters.
+    pub fn parse_geometry(geometry: &[u32]) -> Result<Vec<GeometryCommand>, GeometryError> {
         let mut ret: Vec<GeometryCommand> = Vec::new();
         let mut i = 0;
         while i < geometry.len() {
+            let offset = i;
             let (c_id, count) = Tile::parse_command_integer(geometry[i]);
             i += 1;
 
-            assert!(c_id == 1 || c_id == 2 || c_id == 7);
-            let param_count = if c_id == 7 { 0 } else { 2 };
+            let param_count = match c_id {
+                1 | 2 => 2,
+                7 => 0,
+                _ => return Err(GeometryError::UnknownCommand(c_id, offset)),
+            };
+            if param_count > 0 && (geometry.len() - i) / param_count < count as usize {
+                return Err(GeometryError::TruncatedParameters(offset));
+            }
+            if c_id == 7 && count != 1 {
+                return Err(GeometryError::InvalidClosePathCount(offset));
+            }
 
             for _ in 0..count {
                 if c_id == 1 {
@@ -79,7 +289,64 @@ impl Tile {
                 }
             }
         }
-        ret
+        Ok(ret)
+    }
+
+    // Like parse_geometry, but also checks that the commands make sense for the
+    // geometry type and that the cursor never leaves the i32 range.
+    pub fn decode_geometry(
+        geom_type: GeomType,
+        geometry: &[u32],
+    ) -> Result<Vec<GeometryCommand>, GeometryError> {
+        let commands = Tile::parse_geometry(geometry)?;
+
+        let mut cursor: (i32, i32) = (0, 0);
+        for c in &commands {
+            match (geom_type, c) {
+                (GeomType::Unknown, _) => return Err(GeometryError::UnknownGeometryType),
+                (GeomType::Point, GeometryCommand::MoveTo(..)) => {}
+                (GeomType::Point, GeometryCommand::LineTo(..)) => {
+                    return Err(GeometryError::LineToInPoint)
+                }
+                (GeomType::Point | GeomType::Linestring, GeometryCommand::ClosePath) => {
+                    return Err(GeometryError::ClosePathOutsidePolygon(geom_type))
+                }
+                _ => {}
+            }
+            if let GeometryCommand::MoveTo(dx, dy) | GeometryCommand::LineTo(dx, dy) = c {
+                cursor = cursor
+                    .0
+                    .checked_add(*dx)
+                    .zip(cursor.1.checked_add(*dy))
+                    .ok_or(GeometryError::CoordinateOverflow)?;
+            }
+        }
+        Ok(commands)
+    }
+
+    // Tags are (key index, value index) pairs into the layer's tables. Pairs
+    // pointing outside the tables, or at values without a known type, are skipped.
+    pub fn decode_properties(
+        layer: &vector_tile::tile::Layer,
+        feature: &vector_tile::tile::Feature,
+    ) -> HashMap<String, PropertyValue> {
+        feature
+            .tags
+            .chunks_exact(2)
+            .filter_map(|kv| {
+                let key = layer.keys.get(kv[0] as usize)?;
+                let value = PropertyValue::from_proto(layer.values.get(kv[1] as usize)?)?;
+                Some((key.clone(), value))
+            })
+            .collect()
+    }
+
+    pub fn features<'a>(&'a self, layer_name: &'a str) -> impl Iterator<Item = Feature<'a>> {
+        self.vtile
+            .iter()
+            .flat_map(|vtile| vtile.layers.iter())
+            .filter(move |l| l.name == layer_name)
+            .flat_map(|l| l.features.iter().map(move |f| Feature::new(l, f)))
     }
 
     pub fn parse_command_integer(ci: u32) -> (u32, u32) {
@@ -88,7 +355,32 @@ impl Tile {
         (c_id, count)
     }
     pub fn decode_parameter_integer(pi: u32) -> i32 {
-        (pi as i32 >> 1) ^ (-(pi as i32 & 1))
+        ((pi >> 1) as i32) ^ (-((pi & 1) as i32))
+    }
+
+    pub fn command_integer(c_id: u32, count: u32) -> u32 {
+        (c_id & 0x7) | (count << 3)
+    }
+    pub fn encode_parameter_integer(n: i32) -> u32 {
+        ((n << 1) ^ (n >> 31)) as u32
+    }
+
+    // Converts a point in tile coordinates (0..extent on both axes) to lon/lat.
+    // Latitude is not linear within the tile's bounds, so this goes through the
+    // fractional tile y instead.
+    pub fn tile_point_to_coords(&self, p: (i32, i32), extent: u32) -> Coords {
+        let (z, tx, ty) = self.source_zxy();
+        let n = 2f64.powf(z as f64);
+        let x = tx as f64 + p.0 as f64 / extent as f64;
+        let y = ty as f64 + p.1 as f64 / extent as f64;
+        Coords::from_deg(
+            (PI * (1. - 2. * y / n)).sinh().atan().to_degrees(),
+            x / n * 360. - 180.,
+        )
+    }
+
+    pub fn geometry_to_coords(&self, g: &Geometry, extent: u32) -> Geometry<Coords> {
+        g.map(|p| self.tile_point_to_coords(p, extent))
     }
 
     pub fn bounds(&self) -> BoundingBox {
@@ -129,6 +421,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_integer_encoding_roundtrip() {
+        for (ci, (c_id, count)) in [(9, (1, 1)), (961, (1, 120)), (26, (2, 3)), (15, (7, 1))] {
+            assert_eq!(Tile::command_integer(c_id, count), ci);
+        }
+        for n in [0, 1, -1, 3, -3, 4096, -4096, i32::MAX, i32::MIN] {
+            let pi = Tile::encode_parameter_integer(n);
+            assert_eq!(Tile::decode_parameter_integer(pi), n);
+        }
+        assert_eq!(Tile::encode_parameter_integer(-1), 1);
+        assert_eq!(Tile::encode_parameter_integer(1), 2);
+    }
+
     #[test]
     fn test_parse_geometry() {
         let geometry: Vec<u32> = vec![9, 6, 12, 18, 10, 12, 24, 44, 15];
@@ -138,7 +443,217 @@ mod tests {
             GeometryCommand::LineTo(12, 22),
             GeometryCommand::ClosePath,
         ];
-        assert_eq!(Tile::parse_geometry(&geometry), expected_commands);
+        assert_eq!(Tile::parse_geometry(&geometry), Ok(expected_commands));
+    }
+
+    #[test]
+    fn test_decode_geometry_errors() {
+        let tests = vec![
+            (
+                GeomType::Polygon,
+                vec![9, 6],
+                GeometryError::TruncatedParameters(0),
+            ),
+            (
+                GeomType::Polygon,
+                vec![9, 6, 12, 26, 10, 12],
+                GeometryError::TruncatedParameters(3),
+            ),
+            (
+                GeomType::Polygon,
+                vec![9, 6, 12, 11],
+                GeometryError::UnknownCommand(3, 3),
+            ),
+            (
+                GeomType::Linestring,
+                vec![9, 6, 12, 18, 10, 12, 24, 44, 15],
+                GeometryError::ClosePathOutsidePolygon(GeomType::Linestring),
+            ),
+            (
+                GeomType::Point,
+                vec![9, 6, 12, 10, 2, 2],
+                GeometryError::LineToInPoint,
+            ),
+            (
+                GeomType::Unknown,
+                vec![9, 6, 12],
+                GeometryError::UnknownGeometryType,
+            ),
+            (
+                GeomType::Point,
+                vec![17, u32::MAX - 1, 0, u32::MAX - 1, 0],
+                GeometryError::CoordinateOverflow,
+            ),
+        ];
+        for (geom_type, geometry, expected) in tests {
+            assert_eq!(
+                Tile::decode_geometry(geom_type, &geometry),
+                Err(expected),
+                "decoding {:?}",
+                geometry
+            );
+        }
+        assert_eq!(
+            Tile::decode_geometry(GeomType::Point, &[17, 4, 4, 3, 3]),
+            Ok(vec![
+                GeometryCommand::MoveTo(2, 2),
+                GeometryCommand::MoveTo(-2, -2)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_fuzz_decode() {
+        use prost::Message;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let valid = vector_tile::Tile {
+            layers: vec![vector_tile::tile::Layer {
+                version: 2,
+                name: String::from("water"),
+                keys: vec![String::from("class")],
+                values: vec![vector_tile::tile::Value {
+                    string_value: Some(String::from("ocean")),
+                    ..Default::default()
+                }],
+                features: vec![vector_tile::tile::Feature {
+                    tags: vec![0, 0],
+                    r#type: Some(GeomType::Polygon as i32),
+                    geometry: vec![9, 6, 12, 18, 10, 12, 24, 44, 15],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec();
+
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..5000 {
+            let buf: Vec<u8> = if rng.gen_bool(0.5) {
+                (0..rng.gen::<u8>() % 128).map(|_| rng.gen()).collect()
+            } else {
+                let mut buf = valid.clone();
+                for _ in 0..1 + rng.gen::<u8>() % 3 {
+                    let i = rng.gen::<usize>() % buf.len();
+                    buf[i] = rng.gen();
+                }
+                buf
+            };
+            if let Ok(vtile) = vector_tile::Tile::decode(buf.as_slice()) {
+                for layer in &vtile.layers {
+                    for f in &layer.features {
+                        let f = Feature::new(layer, f);
+                        let _ = f.commands();
+                    }
+                }
+            }
+
+            let geometry: Vec<u32> = (0..rng.gen::<u8>() % 24)
+                .map(|_| match rng.gen::<u8>() % 3 {
+                    0 => rng.gen::<u32>() % 64,
+                    1 => rng.gen(),
+                    _ => 9,
+                })
+                .collect();
+            for geom_type in [
+                GeomType::Unknown,
+                GeomType::Point,
+                GeomType::Linestring,
+                GeomType::Polygon,
+            ] {
+                let _ = Tile::decode_geometry(geom_type, &geometry);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_properties() {
+        use vector_tile::tile::Value;
+        let layer = vector_tile::tile::Layer {
+            name: String::from("place"),
+            keys: vec![
+                String::from("name"),
+                String::from("rank"),
+                String::from("capital"),
+            ],
+            values: vec![
+                Value {
+                    string_value: Some(String::from("Zurich")),
+                    ..Default::default()
+                },
+                Value {
+                    uint_value: Some(3),
+                    ..Default::default()
+                },
+                Value {
+                    bool_value: Some(false),
+                    ..Default::default()
+                },
+            ],
+            features: vec![vector_tile::tile::Feature {
+                // The last pair points past the key table and is dropped.
+                tags: vec![0, 0, 1, 1, 2, 2, 7, 0],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let t = Tile::from_proto(
+            0,
+            0,
+            0,
+            vector_tile::Tile {
+                layers: vec![layer],
+            },
+        );
+
+        let features: Vec<Feature> = t.features("place").collect();
+        assert_eq!(features.len(), 1);
+        let f = &features[0];
+        assert_eq!(f.properties.len(), 3);
+        assert_eq!(f.get_str("name"), Some("Zurich"));
+        assert_eq!(f.get_i64("rank"), Some(3));
+        assert_eq!(f.get_f64("rank"), Some(3.));
+        assert_eq!(f.get("capital"), Some(&PropertyValue::Bool(false)));
+        assert_eq!(t.features("water").count(), 0);
+    }
+
+    #[test]
+    fn test_tile_point_to_coords() {
+        let eps = 1e-9;
+        let t = Tile {
+            zxy: (5, 16, 11),
+            ..Default::default()
+        };
+        let b = t.bounds();
+
+        let nw = t.tile_point_to_coords((0, 0), 4096);
+        let se = t.tile_point_to_coords((4096, 4096), 4096);
+        assert!((nw.lat - b.n).abs() < eps && (nw.lon - b.w).abs() < eps);
+        assert!((se.lat - b.s).abs() < eps && (se.lon - b.e).abs() < eps);
+
+        let g = t.geometry_to_coords(&Geometry::MultiPoint(vec![(2048, 2048)]), 4096);
+        match g {
+            Geometry::MultiPoint(points) => {
+                assert!(b.contains(points[0]));
+                assert!((points[0].lon - (b.w + b.e) / 2.).abs() < eps);
+                // Mercator stretches higher latitudes, so the middle row of a
+                // northern tile lies north of its mean latitude.
+                assert!(points[0].lat > (b.n + b.s) / 2.);
+            }
+            _ => panic!("expected points, got {:?}", g),
+        }
+
+        // An overzoomed tile still places its source's points where they belong.
+        let mut child = Tile {
+            zxy: (7, 16 * 4 + 3, 11 * 4 + 1),
+            ..Default::default()
+        };
+        child.overzoomed_from(t);
+        assert_eq!(child.overzoom, 2);
+        assert_eq!(child.source_zxy(), (5, 16, 11));
+        assert_eq!(child.quadrant(), (3, 1));
+        let c = child.tile_point_to_coords((0, 0), 4096);
+        assert!((c.lat - b.n).abs() < eps && (c.lon - b.w).abs() < eps);
     }
 
     #[test]
@@ -152,7 +667,7 @@ mod tests {
                 ..Default::default()
             };
 
-            for (exp, got, dir) in vec![
+            for (exp, got, dir) in [
                 (b.n, t.bounds().n, "north"),
                 (b.s, t.bounds().s, "south"),
                 (b.e, t.bounds().e, "east"),
@@ -171,8 +686,8 @@ mod tests {
 
     #[test]
     fn test_tile_bounds_cities() {
-        use map_macro::map;
-        let cities = map! {
+        use map_macro::hash_map;
+        let cities = hash_map! {
             "Paris" => (48.8566, 2.349014),
             "Lyon" => (45.763420,4.834277),
             "Barcelona" => (41.3874, 2.1686),
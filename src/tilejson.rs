@@ -0,0 +1,234 @@
+use crate::stats::TileStats;
+use crate::tile::{self, BoundingBox};
+use crate::tilesource::{Fetched, TileServerSource, TileSource, TileSourceError, VectorLayer};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// https://github.com/mapbox/tilejson-spec/tree/master/3.0.0
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TileJson {
+    pub tilejson: Option<String>,
+    pub name: Option<String>,
+    pub attribution: Option<String>,
+    // "xyz" or "tms"
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    pub tiles: Vec<String>,
+    #[serde(default)]
+    pub minzoom: u32,
+    #[serde(default = "default_maxzoom")]
+    pub maxzoom: u32,
+    // [w, s, e, n]
+    pub bounds: Option<[f64; 4]>,
+    // [lon, lat, zoom]
+    pub center: Option<[f64; 3]>,
+    #[serde(default)]
+    pub vector_layers: Vec<VectorLayer>,
+}
+
+// Fetches tiles from the servers listed in a TileJSON document, spreading
+// requests over them and refusing zoom levels they don't have.
+#[derive(Clone)]
+pub struct TileJsonSource {
+    tilejson: TileJson,
+    servers: Vec<TileServerSource>,
+}
+
+fn default_scheme() -> String {
+    String::from("xyz")
+}
+
+fn default_maxzoom() -> u32 {
+    30
+}
+
+impl TileJson {
+    // Reads a document from an http(s) URL or a local file.
+    pub fn load(location: &str) -> Result<Self, TileSourceError> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Self::from_url(location)
+        } else {
+            Self::from_file(location)
+        }
+    }
+
+    pub fn from_url(url: &str) -> Result<Self, TileSourceError> {
+        let res = reqwest::blocking::get(url)?.error_for_status()?;
+        let base = res.url().clone();
+        let mut tilejson: TileJson = serde_json::from_slice(&res.bytes()?)?;
+
+        // Tile URLs may be relative to the document. Joining percent-encodes
+        // the template braces, so those are put back afterwards.
+        for t in tilejson.tiles.iter_mut() {
+            if !t.contains("://") {
+                if let Ok(url) = base.join(t) {
+                    *t = url.to_string().replace("%7B", "{").replace("%7D", "}");
+                }
+            }
+        }
+        Ok(tilejson)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TileSourceError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        self.bounds.map(|[w, s, e, n]| BoundingBox::new(n, s, e, w))
+    }
+
+    pub fn tile_source(self) -> Result<TileJsonSource, TileSourceError> {
+        if self.tiles.is_empty() {
+            return Err(TileSourceError::InvalidArchive(String::from(
+                "TileJSON lists no tile URLs",
+            )));
+        }
+        let servers = self
+            .tiles
+            .iter()
+            .map(|t| match self.scheme.as_str() {
+                "tms" => TileServerSource::new(&t.replace("{y}", "{-y}")),
+                _ => TileServerSource::new(t),
+            })
+            .collect();
+        Ok(TileJsonSource {
+            tilejson: self,
+            servers,
+        })
+    }
+}
+
+impl TileJsonSource {
+    pub fn tilejson(&self) -> &TileJson {
+        &self.tilejson
+    }
+
+    pub fn vector_layers(&self) -> &[VectorLayer] {
+        &self.tilejson.vector_layers
+    }
+
+    fn server(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+    ) -> Result<&mut TileServerSource, TileSourceError> {
+        let (minzoom, maxzoom) = self.zoom_range();
+        if (z as u32) < minzoom || (z as u32) > maxzoom {
+            return Err(TileSourceError::ZoomOutOfRange(z));
+        }
+        // The same tile always goes to the same server, like subdomains.
+        let i = (x as i64 + y as i64).rem_euclid(self.servers.len() as i64) as usize;
+        Ok(&mut self.servers[i])
+    }
+}
+
+impl TileSource for TileJsonSource {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        self.server(z, x, y)?.get_tile(z, x, y)
+    }
+
+    fn fetch(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+        etag: Option<&str>,
+    ) -> Result<Fetched, TileSourceError> {
+        self.server(z, x, y)?.fetch(z, x, y, etag)
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        (self.tilejson.minzoom, self.tilejson.maxzoom)
+    }
+
+    fn stats(&self) -> TileStats {
+        let mut stats = TileStats::default();
+        for server in &self.servers {
+            stats.add(&server.stats());
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::geometry::Geometry;
+    use prost::Message;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // A stand-in for a tile server: answers GET requests for the given paths
+    // and 404s everything else, one connection per request.
+    fn serve(routes: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let path = request.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = match routes.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => ("200 OK", body.clone()),
+                    None => ("404 Not Found", b"not found".to_vec()),
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn test_tilejson_source() {
+        let mut layer = LayerBuilder::new("water");
+        layer.add_feature(None, &Geometry::MultiPoint(vec![(1, 1)]), &HashMap::new());
+        let tile = TileBuilder::new().add_layer(layer).build().encode_to_vec();
+        let doc = br#"{
+            "tilejson": "3.0.0",
+            "tiles": ["/data/v3/{z}/{x}/{y}.pbf"],
+            "minzoom": 0,
+            "maxzoom": 2,
+            "bounds": [-180, -85, 180, 85],
+            "vector_layers": [{"id": "water", "fields": {"class": "String"}}]
+        }"#;
+        let url = serve(vec![
+            ("/data/v3.json", doc.to_vec()),
+            ("/data/v3/1/0/1.pbf", tile),
+        ]);
+
+        let tilejson = TileJson::load(&format!("{url}/data/v3.json")).unwrap();
+        assert_eq!(
+            tilejson.tiles,
+            vec![format!("{url}/data/v3/{{z}}/{{x}}/{{y}}.pbf")]
+        );
+        assert!((tilejson.bounds().unwrap().n - 85.).abs() < 1e-9);
+
+        let mut ts = tilejson.tile_source().unwrap();
+        assert_eq!(ts.zoom_range(), (0, 2));
+        assert_eq!(ts.vector_layers()[0].id, "water");
+        assert_eq!(ts.get_tile(1, 0, 1).unwrap().features("water").count(), 1);
+        assert!(matches!(
+            ts.get_tile(3, 0, 0),
+            Err(TileSourceError::ZoomOutOfRange(3))
+        ));
+
+        assert!(TileJson::load(&format!("{url}/missing.json")).is_err());
+    }
+}
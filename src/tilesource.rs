@@ -1,81 +1,493 @@
+use std::io::Read;
 use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::stats::{self, TileStats};
 use crate::tile;
 use crate::util;
 use crate::vector_tile;
 use lru;
 use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use thiserror::Error;
 
 pub trait TileSource {
     fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError>;
+
+    // Like get_tile, but for sources that know about HTTP caching: `etag` is
+    // the version the caller already has, and the source may answer that it's
+    // still current instead of sending the tile again.
+    fn fetch(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+        etag: Option<&str>,
+    ) -> Result<Fetched, TileSourceError> {
+        Ok(Fetched::Tile(self.get_tile(z, x, y)?, Freshness::default()))
+    }
+
+    // The lowest and highest zoom level the source has tiles for.
+    fn zoom_range(&self) -> (u32, u32) {
+        (DEFAULT_MIN_ZOOM, DEFAULT_MAX_ZOOM)
+    }
+
+    // A tile the source already has at hand, without fetching anything.
+    // Renderers fall back to these when a tile can't be loaded.
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        None
+    }
+
+    // The tiles wanted right now, most important first. Sources that load in
+    // the background use this to order and cancel their work.
+    fn request(&mut self, tiles: &[(usize, i32, i32)]) {}
+
+    // Whether tiles finished loading in the background since the last call.
+    fn poll(&mut self) -> bool {
+        false
+    }
+
+    fn stats(&self) -> TileStats {
+        TileStats::default()
+    }
+}
+
+// OpenMapTiles, which most servers we talk to serve, stops at zoom 14.
+pub const DEFAULT_MIN_ZOOM: u32 = 0;
+pub const DEFAULT_MAX_ZOOM: u32 = 14;
+
+// How long a fetched tile may be reused, as far as its source knows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Freshness {
+    pub etag: Option<String>,
+    pub max_age: Option<Duration>,
+}
+
+pub enum Fetched {
+    Tile(tile::Tile, Freshness),
+    NotModified(Freshness),
 }
 
+// Fetches tiles over HTTP from a URL template.
+#[derive(Clone)]
 pub struct TileServerSource {
-    tileserver: String,
+    template: String,
+    subdomains: Vec<String>,
+    query: Vec<(String, String)>,
+    client: reqwest::blocking::Client,
+    retries: u32,
+    backoff: Duration,
+    stats: TileStats,
 }
 
+pub const DEFAULT_TILESERVER: &str = "http://harababurel.com:8080";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Transient failures are retried this many times, waiting DEFAULT_BACKOFF
+// before the first retry and twice as long before each further one.
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(250);
+// Failed tiles are asked for again after this long.
+pub const DEFAULT_ERROR_TTL: Duration = Duration::from_secs(30);
+// Appended to tile server URLs that don't have a {z}/{x}/{y} template of their own.
+const DEFAULT_TILE_PATH: &str = "/data/v3/{z}/{x}/{y}.pbf";
+
+// A loaded tile or the error loading it failed with, and when that happened.
+pub(crate) type CacheEntry = (Result<tile::Tile, TileSourceError>, Instant);
+
 pub struct CachedTileSource<TS: TileSource> {
     ts: TS,
-    cache: lru::LruCache<(usize, i32, i32), Result<tile::Tile, TileSourceError>>,
+    cache: lru::LruCache<(usize, i32, i32), CacheEntry>,
+    error_ttl: Duration,
+    stats: TileStats,
 }
 
+#[derive(Default)]
 pub struct DummyTileSource {}
 
+// A source layer as listed in TileJSON and MBTiles metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VectorLayer {
+    pub id: String,
+    #[serde(default)]
+    pub fields: HashMap<String, String>,
+    pub minzoom: Option<u32>,
+    pub maxzoom: Option<u32>,
+    pub description: Option<String>,
+}
+
+// Reads tiles from a {z}/{x}/{y}.pbf directory tree on disk.
+pub struct DirectoryTileSource {
+    root: PathBuf,
+    zoom_range: (u32, u32),
+    stats: TileStats,
+}
+
 impl TileServerSource {
-    pub fn new() -> Self {
+    // `url` is either a template like https://{s}.example.com/{z}/{x}/{y}.pbf,
+    // where {s} picks a subdomain and {-y} is the TMS row, or the base URL of
+    // a tileserver-gl instance serving the OpenMapTiles `v3` data set.
+    pub fn new(url: &str) -> Self {
+        let template = if url.contains("{z}") {
+            url.to_string()
+        } else {
+            format!("{}{}", url.trim_end_matches('/'), DEFAULT_TILE_PATH)
+        };
         TileServerSource {
-            tileserver: String::from("http://harababurel.com:8080"),
+            template,
+            subdomains: Vec::new(),
+            query: Vec::new(),
+            client: reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            stats: TileStats::default(),
+        }
+    }
+
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_subdomains(mut self, subdomains: Vec<String>) -> Self {
+        self.subdomains = subdomains;
+        self
+    }
+
+    // Adds a query parameter to every request, e.g. an API key.
+    pub fn with_query(mut self, key: &str, value: &str) -> Self {
+        self.query.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    pub fn tile_url(&self, z: usize, x: i32, y: i32) -> String {
+        // The same tile always goes to the same subdomain, so HTTP caches stay warm.
+        let subdomain = match self.subdomains.len() {
+            0 => "",
+            n => &self.subdomains[(x as i64 + y as i64).rem_euclid(n as i64) as usize],
+        };
+        let url = self
+            .template
+            .replace("{z}", &z.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string())
+            .replace("{-y}", &((1i64 << z) - 1 - y as i64).to_string())
+            .replace("{s}", subdomain);
+        if self.query.is_empty() {
+            return url;
+        }
+        match reqwest::Url::parse_with_params(&url, &self.query) {
+            Ok(url) => url.to_string(),
+            Err(_) => url,
         }
     }
 }
 
+impl Default for TileServerSource {
+    fn default() -> Self {
+        Self::new(DEFAULT_TILESERVER)
+    }
+}
+
 impl<TS: TileSource> CachedTileSource<TS> {
     pub fn with_cap(ts: TS, cap: NonZeroUsize) -> Self {
         CachedTileSource {
             ts,
             cache: lru::LruCache::new(cap),
+            error_ttl: DEFAULT_ERROR_TTL,
+            stats: TileStats::default(),
         }
     }
     pub fn unbounded(ts: TS) -> Self {
         CachedTileSource {
             ts,
             cache: lru::LruCache::unbounded(),
+            error_ttl: DEFAULT_ERROR_TTL,
+            stats: TileStats::default(),
         }
     }
+    pub fn with_error_ttl(mut self, error_ttl: Duration) -> Self {
+        self.error_ttl = error_ttl;
+        self
+    }
+}
+
+impl<TS: TileSource + ?Sized> TileSource for Box<TS> {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        (**self).get_tile(z, x, y)
+    }
+
+    fn fetch(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+        etag: Option<&str>,
+    ) -> Result<Fetched, TileSourceError> {
+        (**self).fetch(z, x, y, etag)
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        (**self).zoom_range()
+    }
+
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        (**self).cached_tile(z, x, y)
+    }
+
+    fn request(&mut self, tiles: &[(usize, i32, i32)]) {
+        (**self).request(tiles)
+    }
+
+    fn poll(&mut self) -> bool {
+        (**self).poll()
+    }
+
+    fn stats(&self) -> TileStats {
+        (**self).stats()
+    }
 }
 
 impl<TS: TileSource> TileSource for CachedTileSource<TS> {
     fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
         let k = (z, x, y);
+        self.stats.requests += 1;
 
-        if !self.cache.contains(&k) {
-            self.cache.push(k, self.ts.get_tile(z, x, y));
+        let expired = match self.cache.peek(&k) {
+            Some((Err(_), at)) => at.elapsed() >= self.error_ttl,
+            Some((Ok(_), _)) => false,
+            None => true,
+        };
+        if expired {
+            self.cache
+                .push(k, (self.ts.get_tile(z, x, y), Instant::now()));
+        } else {
+            self.stats.hits += 1;
         }
 
-        // Workaround: reqwest::Error can't be cloned because of the streamy nature of it.
-        // When we have to return a fresh clone of a tile source error, we just return a new
-        // UndefinedError
-        match self.cache.get(&k).unwrap() {
-            Ok(tile) => Ok(tile.to_owned()),
-            Err(err) => Err(TileSourceError::UnknownError),
+        self.cache.get(&k).unwrap().0.clone()
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        self.ts.zoom_range()
+    }
+
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        match self.cache.peek(&(z, x, y)) {
+            Some((Ok(tile), _)) => Some(tile.to_owned()),
+            _ => self.ts.cached_tile(z, x, y),
         }
     }
+
+    fn stats(&self) -> TileStats {
+        self.stats.with_inner(&self.ts.stats())
+    }
 }
 
 impl TileSource for TileServerSource {
     fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
-        let endpoint = format!("{}/data/v3/{z}/{x}/{y}.pbf", self.tileserver);
+        match self.fetch(z, x, y, None)? {
+            Fetched::Tile(tile, _) => Ok(tile),
+            // Can't happen without an ETag.
+            Fetched::NotModified(_) => Err(TileSourceError::HttpStatus(304)),
+        }
+    }
+
+    fn fetch(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+        etag: Option<&str>,
+    ) -> Result<Fetched, TileSourceError> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_once(z, x, y, etag) {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    let delay = self.backoff * 2u32.pow(attempt);
+                    warn!("Fetching tile {z}/{x}/{y} failed ({e}), retrying in {delay:?}");
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                res => {
+                    self.stats.record_result(&res);
+                    return res;
+                }
+            }
+        }
+    }
+
+    fn stats(&self) -> TileStats {
+        self.stats.clone()
+    }
+}
+
+impl TileServerSource {
+    fn fetch_once(
+        &mut self,
+        z: usize,
+        x: i32,
+        y: i32,
+        etag: Option<&str>,
+    ) -> Result<Fetched, TileSourceError> {
+        let endpoint = self.tile_url(z, x, y);
         info!("Fetching tile from {}", endpoint);
-        let res = reqwest::blocking::get(endpoint)?;
+        let mut req = self.client.get(endpoint);
+        if let Some(etag) = etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        let start = Instant::now();
+        let res = req.send()?;
+        let freshness = freshness(res.headers());
+        match res.status() {
+            reqwest::StatusCode::NOT_MODIFIED => return Ok(Fetched::NotModified(freshness)),
+            reqwest::StatusCode::NOT_FOUND => return Err(TileSourceError::NotFound(z, x, y)),
+            // Servers answer 204 No Content for tiles without any features.
+            reqwest::StatusCode::NO_CONTENT => {
+                self.stats.record_fetch(0, start.elapsed());
+                let tile = tile::Tile::from_proto(x, y, z, vector_tile::Tile::default());
+                return Ok(Fetched::Tile(tile, freshness));
+            }
+            status if !status.is_success() => {
+                return Err(TileSourceError::HttpStatus(status.as_u16()))
+            }
+            _ => {}
+        }
+
         let buf = res.bytes()?;
-        let vtile = vector_tile::Tile::decode(&mut std::io::Cursor::new(buf.clone()))?;
+        self.stats.record_fetch(buf.len(), start.elapsed());
+        let tile = decode_tile_timed(&mut self.stats, z, x, y, &buf)?;
+
+        Ok(Fetched::Tile(tile, freshness))
+    }
+}
+
+// Reads the ETag and Cache-Control max-age of a response; no-cache and
+// no-store make the tile expire right away.
+fn freshness(headers: &reqwest::header::HeaderMap) -> Freshness {
+    let etag = headers
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let max_age = headers
+        .get(reqwest::header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(',').map(str::trim).find_map(|d| match d {
+                "no-cache" | "no-store" => Some(Duration::ZERO),
+                _ => d
+                    .strip_prefix("max-age=")
+                    .and_then(|s| s.parse().ok())
+                    .map(Duration::from_secs),
+            })
+        });
+    Freshness { etag, max_age }
+}
 
-        let tile = tile::Tile::from_proto(x, y, z, vtile);
+impl DirectoryTileSource {
+    // The zoom range is that of the z directories present when the source
+    // is created.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let zoom_range = zoom_levels(&root).unwrap_or((DEFAULT_MIN_ZOOM, DEFAULT_MAX_ZOOM));
+        DirectoryTileSource {
+            root,
+            zoom_range,
+            stats: TileStats::default(),
+        }
+    }
+
+    pub fn tile_path(&self, z: usize, x: i32, y: i32) -> PathBuf {
+        self.root
+            .join(z.to_string())
+            .join(x.to_string())
+            .join(format!("{y}.pbf"))
+    }
+}
+
+impl TileSource for DirectoryTileSource {
+    fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let res = self.read_tile(z, x, y);
+        self.stats.record_result(&res);
+        res
+    }
+
+    fn zoom_range(&self) -> (u32, u32) {
+        self.zoom_range
+    }
 
-        Ok(tile)
+    // Tiles on disk are always at hand.
+    fn cached_tile(&mut self, z: usize, x: i32, y: i32) -> Option<tile::Tile> {
+        self.read_tile(z, x, y).ok()
+    }
+
+    fn stats(&self) -> TileStats {
+        self.stats.clone()
+    }
+}
+
+// The lowest and highest zoom level a tile directory has a subdirectory for.
+fn zoom_levels(root: &Path) -> Option<(u32, u32)> {
+    let levels: Vec<u32> = std::fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    Some((*levels.iter().min()?, *levels.iter().max()?))
+}
+
+impl DirectoryTileSource {
+    fn read_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+        let path = self.tile_path(z, x, y);
+        let (buf, fetch_time) = stats::timed(|| std::fs::read(&path));
+        let buf = match buf {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(TileSourceError::NotFound(z, x, y));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.stats.record_fetch(buf.len(), fetch_time);
+        decode_tile_timed(&mut self.stats, z, x, y, &buf)
     }
 }
+
+// Tile payloads are often stored gzipped; those are recognized by their magic bytes.
+pub fn decode_tile(z: usize, x: i32, y: i32, buf: &[u8]) -> Result<tile::Tile, TileSourceError> {
+    let vtile = if buf.starts_with(&[0x1f, 0x8b]) {
+        let mut raw = Vec::new();
+        flate2::read::GzDecoder::new(buf)
+            .read_to_end(&mut raw)
+            .map_err(|e| TileSourceError::DecodeError(e.to_string()))?;
+        vector_tile::Tile::decode(raw.as_slice())?
+    } else {
+        vector_tile::Tile::decode(buf)?
+    };
+    Ok(tile::Tile::from_proto(x, y, z, vtile))
+}
+
+// decode_tile, recording the time it took.
+pub fn decode_tile_timed(
+    stats: &mut TileStats,
+    z: usize,
+    x: i32,
+    y: i32,
+    buf: &[u8],
+) -> Result<tile::Tile, TileSourceError> {
+    let (res, decode_time) = stats::timed(|| decode_tile(z, x, y, buf));
+    stats.record_decode(decode_time);
+    res
+}
+
 impl TileSource for DummyTileSource {
     fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
         Ok(tile::Tile::from_proto(
@@ -87,12 +499,268 @@ impl TileSource for DummyTileSource {
     }
 }
 
-#[derive(Error, Debug)]
+// Errors are cloned out of caches, so the ones wrapping non-cloneable
+// library errors keep them behind an Arc.
+#[derive(Error, Debug, Clone)]
 pub enum TileSourceError {
-    #[error("some network error")]
-    NetworkError(#[from] reqwest::Error),
-    #[error("could not decode proto")]
-    ProtoDecodeError(#[from] prost::DecodeError),
-    #[error("unknown error")]
-    UnknownError,
+    #[error("network error: {0}")]
+    NetworkError(Arc<reqwest::Error>),
+    #[error("tile server answered with HTTP status {0}")]
+    HttpStatus(u16),
+    #[error("request timed out")]
+    Timeout,
+    #[error("could not decode tile: {0}")]
+    DecodeError(String),
+    #[error("I/O error: {0}")]
+    IoError(Arc<std::io::Error>),
+    #[error("tile {0}/{1}/{2} not found")]
+    NotFound(usize, i32, i32),
+    #[error("tile {0}/{1}/{2} is still loading")]
+    Loading(usize, i32, i32),
+    #[error("zoom {0} is outside the range of the tile source")]
+    ZoomOutOfRange(usize),
+    #[error("invalid tile archive: {0}")]
+    InvalidArchive(String),
+    #[error("SQLite error: {0}")]
+    SqliteError(Arc<rusqlite::Error>),
+    #[error("JSON error: {0}")]
+    SerdeJsonError(Arc<serde_json::Error>),
+}
+
+impl TileSourceError {
+    // Whether asking again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TileSourceError::NetworkError(_) | TileSourceError::Timeout => true,
+            TileSourceError::HttpStatus(status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TileSourceError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            TileSourceError::Timeout
+        } else if let Some(status) = e.status() {
+            TileSourceError::HttpStatus(status.as_u16())
+        } else {
+            TileSourceError::NetworkError(Arc::new(e))
+        }
+    }
+}
+
+impl From<prost::DecodeError> for TileSourceError {
+    fn from(e: prost::DecodeError) -> Self {
+        TileSourceError::DecodeError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for TileSourceError {
+    fn from(e: std::io::Error) -> Self {
+        TileSourceError::IoError(Arc::new(e))
+    }
+}
+
+impl From<rusqlite::Error> for TileSourceError {
+    fn from(e: rusqlite::Error) -> Self {
+        TileSourceError::SqliteError(Arc::new(e))
+    }
+}
+
+impl From<serde_json::Error> for TileSourceError {
+    fn from(e: serde_json::Error) -> Self {
+        TileSourceError::SerdeJsonError(Arc::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{LayerBuilder, TileBuilder};
+    use crate::geometry::Geometry;
+    use flate2::write::GzEncoder;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn sample_tile() -> Vec<u8> {
+        let mut layer = LayerBuilder::new("place");
+        layer.add_feature(None, &Geometry::MultiPoint(vec![(10, 20)]), &HashMap::new());
+        TileBuilder::new().add_layer(layer).build().encode_to_vec()
+    }
+
+    // A stand-in tile server that answers requests with the given statuses
+    // in turn, sending `body` along with the 200s and nothing with the 204s,
+    // and counts the requests.
+    fn serve_statuses(statuses: Vec<u16>, body: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let body = match status {
+                    200 => &body[..],
+                    204 => &[],
+                    _ => b"error",
+                };
+                let head = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn test_tile_server_status_and_retries() {
+        let backoff = Duration::from_millis(1);
+
+        let (url, requests) = serve_statuses(vec![503, 429, 200], sample_tile());
+        let mut ts = TileServerSource::new(&url).with_retries(3, backoff);
+        assert_eq!(ts.get_tile(1, 0, 0).unwrap().features("place").count(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Not found isn't retried, and the error page isn't decoded.
+        let (url, requests) = serve_statuses(vec![404], Vec::new());
+        let mut ts = TileServerSource::new(&url).with_retries(3, backoff);
+        assert!(matches!(
+            ts.get_tile(1, 0, 0),
+            Err(TileSourceError::NotFound(1, 0, 0))
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let (url, requests) = serve_statuses(vec![500; 3], Vec::new());
+        let mut ts = TileServerSource::new(&url).with_retries(2, backoff);
+        assert!(matches!(
+            ts.get_tile(1, 0, 0),
+            Err(TileSourceError::HttpStatus(500))
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // No Content is an empty tile.
+        let (url, _) = serve_statuses(vec![204], Vec::new());
+        let mut ts = TileServerSource::new(&url);
+        assert!(ts
+            .get_tile(1, 0, 0)
+            .unwrap()
+            .vtile
+            .unwrap()
+            .layers
+            .is_empty());
+    }
+
+    // Fails the first request for every tile.
+    #[derive(Default)]
+    struct FlakySource {
+        calls: usize,
+    }
+
+    impl TileSource for FlakySource {
+        fn get_tile(&mut self, z: usize, x: i32, y: i32) -> Result<tile::Tile, TileSourceError> {
+            self.calls += 1;
+            match self.calls {
+                1 => Err(TileSourceError::HttpStatus(503)),
+                _ => DummyTileSource::default().get_tile(z, x, y),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cached_errors() {
+        let mut ts = CachedTileSource::unbounded(FlakySource::default());
+        for _ in 0..2 {
+            assert!(matches!(
+                ts.get_tile(0, 0, 0),
+                Err(TileSourceError::HttpStatus(503))
+            ));
+        }
+        assert_eq!(ts.ts.calls, 1);
+
+        let mut ts =
+            CachedTileSource::unbounded(FlakySource::default()).with_error_ttl(Duration::ZERO);
+        assert!(ts.get_tile(0, 0, 0).is_err());
+        assert!(ts.get_tile(0, 0, 0).is_ok());
+        assert!(ts.get_tile(0, 0, 0).is_ok());
+        assert_eq!(ts.ts.calls, 2);
+    }
+
+    #[test]
+    fn test_cached_stats() {
+        let mut ts = CachedTileSource::unbounded(DummyTileSource::default());
+        ts.get_tile(0, 0, 0).unwrap();
+        ts.get_tile(0, 0, 0).unwrap();
+        let stats = ts.stats();
+        assert_eq!((stats.requests, stats.hits), (2, 1));
+    }
+
+    #[test]
+    fn test_tile_url() {
+        let ts = TileServerSource::new("http://localhost:8080/");
+        assert_eq!(
+            ts.tile_url(3, 4, 5),
+            "http://localhost:8080/data/v3/3/4/5.pbf"
+        );
+
+        let ts = TileServerSource::new("https://{s}.tiles.example/{z}/{x}/{-y}.mvt?style=dark")
+            .with_subdomains(vec!["a".into(), "b".into()])
+            .with_query("key", "s3cr&t");
+        assert_eq!(
+            ts.tile_url(2, 1, 0),
+            "https://b.tiles.example/2/1/3.mvt?style=dark&key=s3cr%26t"
+        );
+        assert!(ts
+            .tile_url(2, 1, 1)
+            .starts_with("https://a.tiles.example/2/1/2.mvt"));
+    }
+
+    #[test]
+    fn test_directory_tile_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ts = DirectoryTileSource::new(dir.path());
+
+        let raw = sample_tile();
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&raw).unwrap();
+        let gzipped = gz.finish().unwrap();
+
+        for ((z, x, y), buf) in [((1, 0, 1), &raw), ((2, 3, 1), &gzipped)] {
+            let path = ts.tile_path(z, x, y);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, buf).unwrap();
+
+            let t = ts.get_tile(z, x, y).unwrap();
+            assert_eq!(t.zxy, (z, x, y));
+            assert_eq!(t.features("place").count(), 1);
+        }
+
+        assert!(matches!(
+            ts.get_tile(3, 1, 1),
+            Err(TileSourceError::NotFound(3, 1, 1))
+        ));
+
+        std::fs::create_dir_all(ts.tile_path(4, 0, 0).parent().unwrap()).unwrap();
+        std::fs::write(ts.tile_path(4, 0, 0), [0x1f, 0x8b, 0xff]).unwrap();
+        assert!(matches!(
+            ts.get_tile(4, 0, 0),
+            Err(TileSourceError::DecodeError(_))
+        ));
+
+        // The zoom range follows the z directories on disk.
+        assert_eq!(ts.zoom_range(), (DEFAULT_MIN_ZOOM, DEFAULT_MAX_ZOOM));
+        assert_eq!(DirectoryTileSource::new(dir.path()).zoom_range(), (1, 4));
+    }
 }
@@ -1,12 +1,13 @@
 use crate::tile::BoundingBox;
 use crate::vector_tile;
+use image::Rgb;
 use std::f64::consts::PI;
 
 const PROJECT_SIZE: u32 = 256;
 
 // https://wiki.openstreetmap.org/wiki/Slippy_map_tilenames
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Coords {
     pub lat: f64,
     pub lon: f64,
@@ -30,10 +31,32 @@ impl TileCoords {
     }
 }
 
+// The same longitude between -180 (inclusive) and 180 degrees.
+pub fn normalize_lon(lon: f64) -> f64 {
+    (lon + 180.).rem_euclid(360.) - 180.
+}
+
 pub fn rectangles_intersect(r1: ((i32, i32), (i32, i32)), r2: ((i32, i32), (i32, i32))) -> bool {
     (r1.0 .0 < r2.1 .0) && (r1.1 .0 > r2.0 .0) && (r1.0 .1 < r2.1 .1) && (r1.1 .1 > r2.0 .1)
 }
 
+// Nearest entry of the xterm 256-color palette: the 6x6x6 color cube, or the
+// grayscale ramp for neutral colors.
+pub fn rgb_to_ansi256(c: Rgb<u8>) -> u8 {
+    let [r, g, b] = c.0;
+    if r == g && g == b {
+        if r < 8 {
+            return 16;
+        }
+        if r > 248 {
+            return 231;
+        }
+        return 232 + ((r as f64 - 8.) / 247. * 24.).round() as u8;
+    }
+    let q = |v: u8| (v as f64 / 255. * 5.).round() as u8;
+    16 + 36 * q(r) + 6 * q(g) + q(b)
+}
+
 pub fn coords_to_tile(c: &Coords, zoom: f64) -> TileCoords {
     let lat_rad = c.lat.to_radians();
     let n = 2f64.powf(zoom);